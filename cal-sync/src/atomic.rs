@@ -0,0 +1,40 @@
+//! Replacing files in one go, so that a crash can't leave half of one behind, and anything
+//! reading them sees either the old one or the new one.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Result;
+
+/// Where a new version of `path` is written before it's moved over it: the whole file name with
+/// `.tmp` on the end, so that `cal-sync.json` and `cal-sync.toml` don't share one.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Replaces the file at `path` with `data`. The new file is created with `mode` (less the
+/// umask) on Unix, and is on disk before it takes the old one's place.
+pub fn write(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let tmp = tmp_path(path);
+    // Permissions are only set on creation, so start from scratch.
+    let _ = fs::remove_file(&tmp);
+
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = opts.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! The interface between the sync logic and whatever calendar service we're writing to.
+
+use std::{fmt, path::PathBuf, time::Duration};
+
+use color_eyre::Result;
+use orgize::TextRange;
+
+use crate::org::{stable_hash, Dateish, Reminder};
+
+/// An event as we want it to appear in the target calendar.
+#[derive(Debug, Clone)]
+pub struct SyncEvent {
+    /// Identifies the org timestamp this event was generated from. See [`AgendaItem::key`].
+    ///
+    /// [`AgendaItem::key`]: crate::org::AgendaItem::key
+    pub key: String,
+    pub summary: String,
+    pub description: String,
+    pub location: Option<String>,
+    pub start: Dateish,
+    pub end: Option<Dateish>,
+    /// An `RRULE:...` line, if the timestamp repeats.
+    pub recurrence: Option<String>,
+    pub color: String,
+    /// `None` leaves reminders up to the calendar.
+    pub reminders: Option<Vec<Reminder>>,
+    pub origin: Origin,
+}
+
+/// Where in the org files an event came from, for writing changes back.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub path: PathBuf,
+    pub range: TextRange,
+    /// The timestamp as written in the file.
+    pub raw: String,
+    /// Whether the timestamp has its own end, or if [`SyncEvent::end`] is a default we made up.
+    pub has_end: bool,
+    /// Whether this is clocked time, which is a record of what happened, so never written back.
+    pub clocked: bool,
+    /// The (1-based) line the timestamp is on, for telling people where it is.
+    pub line: usize,
+}
+
+impl SyncEvent {
+    /// Hashes everything about this event that ends up in the calendar.
+    /// If this changes between runs, the remote event needs to be updated.
+    pub fn hash(&self) -> String {
+        let start = self.start.to_string();
+        let end = self.end.as_ref().map(|e| e.to_string()).unwrap_or_default();
+        let reminders = self.reminders.as_ref().map(|rs| {
+            rs.iter()
+                .map(|r| format!("{:?} {}", r.method, r.minutes))
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+
+        let mut parts = vec![
+            self.summary.as_bytes(),
+            self.description.as_bytes(),
+            self.location.as_deref().unwrap_or_default().as_bytes(),
+            start.as_bytes(),
+            end.as_bytes(),
+            self.recurrence.as_deref().unwrap_or_default().as_bytes(),
+            self.color.as_bytes(),
+        ];
+        // Only hashed when set, so events from before we had reminders don't all change.
+        if let Some(reminders) = &reminders {
+            parts.push(reminders.as_bytes());
+        }
+
+        stable_hash(parts)
+    }
+}
+
+/// An event in the target calendar that was created by us.
+#[derive(Debug, Clone)]
+pub struct RemoteEvent {
+    /// Backend-specific identifier used to update or delete the event.
+    pub id: String,
+    pub summary: String,
+    pub start: Option<Dateish>,
+    pub end: Option<Dateish>,
+    /// The [`SyncEvent::key`] and [`SyncEvent::hash`] this event was written with.
+    /// Events written by older versions of cal-sync don't have these.
+    pub key: Option<String>,
+    pub hash: Option<String>,
+}
+
+/// A calendar that cal-sync can manage events in.
+pub trait CalendarBackend {
+    /// Lists every event in the calendar that was created by cal-sync.
+    async fn list(&self) -> Result<Vec<RemoteEvent>>;
+
+    /// Creates a new event, returning its [`RemoteEvent::id`].
+    async fn insert(&self, ev: &SyncEvent) -> Result<String>;
+
+    /// Overwrites the remote event `id` with `ev`.
+    async fn update(&self, id: &str, ev: &SyncEvent) -> Result<()>;
+
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    /// How many operations the `*_batch` methods can do in one request.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Inserts every event in `evs`, which is at most [`batch_size`](Self::batch_size) long,
+    /// returning a result for each.
+    async fn insert_batch(&self, evs: &[&SyncEvent]) -> Vec<Result<String>> {
+        let mut out = vec![];
+        for ev in evs {
+            out.push(self.insert(ev).await);
+        }
+        out
+    }
+
+    /// Like [`insert_batch`](Self::insert_batch), for updates.
+    async fn update_batch(&self, evs: &[(&str, &SyncEvent)]) -> Vec<Result<()>> {
+        let mut out = vec![];
+        for (id, ev) in evs {
+            out.push(self.update(id, ev).await);
+        }
+        out
+    }
+
+    /// Like [`insert_batch`](Self::insert_batch), for deletes.
+    async fn delete_batch(&self, ids: &[&str]) -> Vec<Result<()>> {
+        let mut out = vec![];
+        for id in ids {
+            out.push(self.delete(id).await);
+        }
+        out
+    }
+}
+
+/// An error response from a calendar service, kept around so we can tell whether trying again
+/// might help.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    /// What we were doing, like `PUT https://...`.
+    pub request: String,
+    pub status: u16,
+    pub message: String,
+    /// How long the service asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    /// Whether the request might succeed later: rate limits and server errors.
+    pub fn is_retryable(&self) -> bool {
+        match self.status {
+            429 | 500..=599 => true,
+            // Google signals rate limits with a 403 as well.
+            403 => {
+                self.message.contains("rateLimitExceeded")
+                    || self.message.contains("userRateLimitExceeded")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.request, self.status)?;
+        if !self.message.is_empty() {
+            write!(f, " {}", self.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = jiff::fmt::rfc2822::parse(value).ok()?;
+    let wait = at.timestamp().as_second() - jiff::Timestamp::now().as_second();
+    Some(Duration::from_secs(wait.max(0) as u64))
+}
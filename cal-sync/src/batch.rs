@@ -0,0 +1,177 @@
+//! Google's batch endpoint, which runs many API requests in a single HTTP request.
+//!
+//! The body is `multipart/mixed`, with each part an HTTP request of its own; the response has
+//! one part per request, matched up by `Content-ID`. See
+//! <https://developers.google.com/calendar/api/guides/batch>.
+
+use color_eyre::{eyre::OptionExt, Result};
+use reqwest::header;
+
+use crate::{
+    backend::{parse_retry_after, ApiError},
+    org::stable_hash,
+};
+
+/// The most requests Google allows in one batch.
+pub const MAX_PARTS: usize = 50;
+
+/// One request in a batch.
+#[derive(Debug)]
+pub struct Part {
+    pub method: &'static str,
+    /// Relative to the API's host, like `/calendar/v3/calendars/.../events`.
+    pub path: String,
+    pub body: Option<serde_json::Value>,
+}
+
+/// The response to one [`Part`].
+#[derive(Debug)]
+pub struct PartResponse {
+    pub status: u16,
+    /// With lower-cased names.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl PartResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Sends `parts` as one batch, returning the response to each part, or `None` for parts the
+/// response didn't cover.
+pub async fn send(
+    http: &reqwest::Client,
+    url: &str,
+    access_token: &str,
+    parts: &[Part],
+) -> Result<Vec<Option<PartResponse>>> {
+    let body = encode(parts);
+    // Derived from the body, so it's vanishingly unlikely to turn up in it.
+    let boundary = format!("batch_{}", stable_hash([body.as_bytes()]));
+    let body = body.replace(BOUNDARY_PLACEHOLDER, &boundary);
+
+    let resp = http
+        .post(url)
+        .bearer_auth(access_token)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/mixed; boundary={boundary}"),
+        )
+        .body(body)
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        return Err(ApiError {
+            request: format!("POST {url}"),
+            status: resp.status().as_u16(),
+            retry_after: resp
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after),
+            message: resp.text().await.unwrap_or_default(),
+        }
+        .into());
+    }
+
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body = resp.text().await?;
+
+    decode(&content_type, &body, parts.len())
+}
+
+/// Stands in for the boundary until we know the body, so that we can pick one that isn't in it.
+const BOUNDARY_PLACEHOLDER: &str = "\u{0}boundary\u{0}";
+
+fn encode(parts: &[Part]) -> String {
+    let mut out = String::new();
+    for (i, p) in parts.iter().enumerate() {
+        out.push_str(&format!(
+            "--{BOUNDARY_PLACEHOLDER}\r\n\
+             Content-Type: application/http\r\n\
+             Content-ID: <item-{i}>\r\n\
+             \r\n\
+             {} {}\r\n",
+            p.method, p.path
+        ));
+        match &p.body {
+            Some(body) => out.push_str(&format!(
+                "Content-Type: application/json; charset=UTF-8\r\n\r\n{body}\r\n"
+            )),
+            None => out.push_str("\r\n"),
+        }
+    }
+    out.push_str(&format!("--{BOUNDARY_PLACEHOLDER}--\r\n"));
+
+    out
+}
+
+/// Splits a multipart batch response into the responses to each of `n` parts.
+fn decode(content_type: &str, body: &str, n: usize) -> Result<Vec<Option<PartResponse>>> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("boundary="))
+        .map(|b| b.trim_matches('"'))
+        .ok_or_eyre("Batch response isn't multipart")?;
+
+    let mut out: Vec<Option<PartResponse>> = (0..n).map(|_| None).collect();
+    for part in body.split(&format!("--{boundary}")) {
+        let Some((part_headers, response)) = split_head(part.trim_start()) else {
+            continue;
+        };
+        let Some(index) = headers(part_headers)
+            .into_iter()
+            .find(|(k, _)| k == "content-id")
+            .and_then(|(_, v)| {
+                v.trim_matches(['<', '>'])
+                    .strip_prefix("response-item-")?
+                    .parse::<usize>()
+                    .ok()
+            })
+            .filter(|i| *i < n)
+        else {
+            continue;
+        };
+
+        let (head, body) = split_head(response).unwrap_or((response, ""));
+        let (status_line, head) = head.split_once('\n').unwrap_or((head, ""));
+        let Some(status) = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+        else {
+            continue;
+        };
+
+        out[index] = Some(PartResponse {
+            status,
+            headers: headers(head),
+            body: body.trim().to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+/// Splits headers from the body that follows them.
+fn split_head(s: &str) -> Option<(&str, &str)> {
+    s.split_once("\r\n\r\n").or_else(|| s.split_once("\n\n"))
+}
+
+fn headers(head: &str) -> Vec<(String, String)> {
+    head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect()
+}
@@ -0,0 +1,222 @@
+//! Syncing to a CalDAV (RFC 4791) calendar collection, e.g. on Fastmail, Nextcloud or Radicale.
+//!
+//! Every event we manage lives in its own `cal-sync-<hash>.ics` resource, which we write with PUT
+//! and remove with DELETE. We find our events again by the custom properties in [`crate::ics`].
+
+use color_eyre::{eyre::WrapErr, Result};
+use quick_xml::events::Event as XmlEvent;
+use reqwest::{header, Method, StatusCode, Url};
+use tracing::debug;
+
+use crate::{
+    backend::{parse_retry_after, ApiError, CalendarBackend, RemoteEvent, SyncEvent},
+    ics,
+    org::stable_hash,
+};
+
+const CALENDAR_QUERY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <D:getetag/>
+    <C:calendar-data/>
+  </D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT"/>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>
+"#;
+
+/// Syncs to a single CalDAV calendar collection.
+pub struct CalDavBackend {
+    http: reqwest::Client,
+    /// The calendar collection. Always ends in a `/`.
+    url: Url,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl CalDavBackend {
+    pub fn new(url: &str, user: Option<String>, password: Option<String>) -> Result<Self> {
+        let mut url = Url::parse(url).wrap_err_with(|| format!("Invalid CalDAV URL {url}"))?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url,
+            user,
+            password,
+        })
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        let req = self.http.request(method, url);
+
+        match &self.user {
+            Some(user) => req.basic_auth(user, self.password.as_ref()),
+            None => req,
+        }
+    }
+
+    /// Resolves a (possibly relative) href returned by the server.
+    fn resolve(&self, href: &str) -> Result<Url> {
+        self.url
+            .join(href)
+            .wrap_err_with(|| format!("Invalid href {href}"))
+    }
+
+    async fn put(&self, url: Url, ev: &SyncEvent, create: bool) -> Result<()> {
+        let mut req = self
+            .request(Method::PUT, url.clone())
+            .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(ics::calendar([ev]));
+        if create {
+            req = req.header(header::IF_NONE_MATCH, "*");
+        }
+
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            return Err(api_error(format!("PUT {url}"), resp).await.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl CalendarBackend for CalDavBackend {
+    async fn list(&self) -> Result<Vec<RemoteEvent>> {
+        let resp = self
+            .request(Method::from_bytes(b"REPORT")?, self.url.clone())
+            .header("Depth", "1")
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(CALENDAR_QUERY)
+            .send()
+            .await?;
+        if resp.status() != StatusCode::MULTI_STATUS {
+            return Err(api_error(format!("REPORT {}", self.url), resp).await.into());
+        }
+
+        let body = resp.text().await?;
+        let mut evs = vec![];
+        for (href, data) in parse_multistatus(&body)? {
+            // Time zones come first, and have DTSTARTs of their own.
+            let lines: Vec<_> = ics::content_lines(&data)
+                .into_iter()
+                .skip_while(|(n, _, v)| !(n == "BEGIN" && v == "VEVENT"))
+                .collect();
+            let find = |name: &str| lines.iter().find(|(n, _, _)| n == name);
+            let get = |name: &str| find(name).map(|(_, _, v)| ics::unescape(v));
+            let date = |name: &str| find(name).and_then(|(_, p, v)| ics::parse_date(p, v));
+
+            // Only look at events that we wrote.
+            let Some(key) = get(ics::KEY_PROP) else {
+                continue;
+            };
+
+            debug!("found {href}");
+            evs.push(RemoteEvent {
+                id: href,
+                summary: get("SUMMARY").unwrap_or_default(),
+                start: date("DTSTART"),
+                end: date("DTEND"),
+                key: Some(key),
+                hash: get(ics::HASH_PROP),
+            });
+        }
+
+        Ok(evs)
+    }
+
+    async fn insert(&self, ev: &SyncEvent) -> Result<String> {
+        let name = format!("cal-sync-{}.ics", stable_hash([ev.key.as_bytes()]));
+        let url = self.resolve(&name)?;
+        self.put(url.clone(), ev, true).await?;
+
+        Ok(url.path().to_string())
+    }
+
+    async fn update(&self, id: &str, ev: &SyncEvent) -> Result<()> {
+        self.put(self.resolve(id)?, ev, false).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let url = self.resolve(id)?;
+        let resp = self.request(Method::DELETE, url.clone()).send().await?;
+
+        // If it's already gone, that's fine too.
+        if !resp.status().is_success() && resp.status() != StatusCode::NOT_FOUND {
+            return Err(api_error(format!("DELETE {url}"), resp).await.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns a failed response into an [`ApiError`], keeping its body, which usually says what went
+/// wrong.
+async fn api_error(request: String, resp: reqwest::Response) -> ApiError {
+    let status = resp.status().as_u16();
+    let retry_after = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+
+    ApiError {
+        request,
+        status,
+        message: resp.text().await.unwrap_or_default().trim().to_string(),
+        retry_after,
+    }
+}
+
+/// Pulls `(href, calendar-data)` pairs out of a multistatus response.
+fn parse_multistatus(body: &str) -> Result<Vec<(String, String)>> {
+    let mut reader = quick_xml::Reader::from_str(body);
+    reader.config_mut().trim_text(true);
+
+    let mut res = vec![];
+    let mut href = None;
+    let mut data = None;
+    // The local name of the element we're currently reading text from, if we care about it.
+    let mut current: Option<Vec<u8>> = None;
+
+    loop {
+        match reader.read_event()? {
+            XmlEvent::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                if name == b"href" || name == b"calendar-data" {
+                    current = Some(name);
+                }
+            }
+            XmlEvent::Text(t) => {
+                let text = t.unescape()?.into_owned();
+                match current.as_deref() {
+                    Some(b"href") => href = Some(text),
+                    Some(b"calendar-data") => data = Some(text),
+                    _ => {}
+                }
+            }
+            XmlEvent::CData(t) => {
+                if current.as_deref() == Some(b"calendar-data") {
+                    data = Some(String::from_utf8_lossy(&t).into_owned());
+                }
+            }
+            XmlEvent::End(e) => {
+                current = None;
+                if e.local_name().as_ref() == b"response" {
+                    if let (Some(h), Some(d)) = (href.take(), data.take()) {
+                        res.push((h, d));
+                    }
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(res)
+}
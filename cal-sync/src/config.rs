@@ -0,0 +1,623 @@
+//! The config file, `~/.config/org-tools/cal-sync.toml` by default.
+//!
+//! ```toml
+//! default_duration = "1:00"
+//! tz = "America/Los_Angeles"
+//! default_calendar = "personal"
+//!
+//! [calendars.personal]
+//! name = "Personal"
+//!
+//! [calendars.work]
+//! name = "Work"
+//! color = "5"
+//! reminders = "10min, email 1d"
+//!
+//! [calendars.family]
+//! name = "Family"
+//!
+//! [[rules]]
+//! calendar = "work"
+//! tags = ["w"]
+//! title = "{todo} {title}"
+//!
+//! [[rules]]
+//! calendar = "family"
+//! tags = ["big_event"]
+//!
+//! [calendars.timesheet]
+//! name = "Timesheet"
+//!
+//! [clock]
+//! calendar = "timesheet"
+//! colors = { acme = "5" }
+//!
+//! [filter]
+//! exclude = ["noexport", "TODO=\"SOMEDAY\""]
+//! ```
+//!
+//! Anything given on the command line overrides what's in here.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use jiff::tz::{Disambiguation, TimeZone};
+use serde::Deserialize;
+
+use crate::{
+    filter::Match,
+    gcal::Endpoints,
+    org::{parse_duration, parse_reminders, stable_hash, AgendaItem, Context, Reminder},
+    retry::Retry,
+};
+
+/// Google Calendar's event colors are numbered 1 through 11.
+const MAX_COLOR: u8 = 11;
+const PLACEHOLDERS: [&str; 5] = ["{title}", "{todo}", "{priority}", "{category}", "{file}"];
+
+/// Which kind of calendar we're syncing to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Google,
+    CalDav,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "google" => Ok(Backend::Google),
+            "caldav" => Ok(Backend::CalDav),
+            _ => Err(format!("unknown backend {s}, expected google or caldav")),
+        }
+    }
+}
+
+/// How we sign in to Google when there isn't a token to refresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Auth {
+    /// Open the consent page in a browser, and listen for it to redirect back to us.
+    Loopback,
+    /// Print the consent page, and have the user paste back where it redirected them to.
+    Manual,
+    /// Sign in as the service account whose key is `creds`.
+    ServiceAccount,
+}
+
+impl FromStr for Auth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "loopback" => Ok(Auth::Loopback),
+            "manual" => Ok(Auth::Manual),
+            "service-account" => Ok(Auth::ServiceAccount),
+            _ => Err(format!(
+                "unknown auth {s}, expected loopback, manual or service-account"
+            )),
+        }
+    }
+}
+
+/// What to do with org times that DST transitions skip over (gaps) or repeat (folds).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dst {
+    /// After a gap, and the earlier of the two in a fold, like most calendar apps.
+    Compatible,
+    /// The earlier of the two possible times.
+    Earlier,
+    /// The later of the two possible times.
+    Later,
+    /// Skip the timestamp, with a warning.
+    Reject,
+}
+
+impl Dst {
+    pub fn disambiguation(self) -> Disambiguation {
+        match self {
+            Dst::Compatible => Disambiguation::Compatible,
+            Dst::Earlier => Disambiguation::Earlier,
+            Dst::Later => Disambiguation::Later,
+            Dst::Reject => Disambiguation::Reject,
+        }
+    }
+}
+
+impl FromStr for Dst {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compatible" => Ok(Dst::Compatible),
+            "earlier" => Ok(Dst::Earlier),
+            "later" => Ok(Dst::Later),
+            "reject" => Ok(Dst::Reject),
+            _ => Err(format!(
+                "unknown dst policy {s}, expected compatible, earlier, later or reject"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub todo_keywords: Vec<String>,
+    pub done_keywords: Vec<String>,
+    /// How long events without an end or `:EFFORT:` are, as an org duration like `1:00`.
+    pub default_duration: String,
+    /// Template for event titles. See [`PLACEHOLDERS`].
+    pub title: String,
+    pub color: String,
+    /// The IANA time zone org timestamps are in, unless a headline's `:TIMEZONE:` says
+    /// otherwise. The system's if not given.
+    pub tz: Option<String>,
+    pub dst: Dst,
+    /// Timestamps in these parts of an entry aren't synced.
+    pub ignore_timestamps_in: Vec<Context>,
+    /// Don't sync anything if an org file can't be read or has invalid timestamps, instead of
+    /// skipping what's wrong.
+    pub strict: bool,
+    /// Port the OAuth redirect listener binds to. Any free one if not given.
+    pub port: Option<u16>,
+    /// How many requests to have in flight at once.
+    pub concurrency: usize,
+    /// How many times to retry requests that fail because of rate limits or server errors.
+    pub retries: u32,
+    /// With `--watch`, how long saves have to stop for before we sync, in milliseconds.
+    pub debounce_ms: u64,
+    /// With `--watch`, how often every file is re-read and every calendar synced, in minutes.
+    pub full_sync_minutes: u64,
+
+    pub backend: Backend,
+    pub auth: Auth,
+    /// The OAuth client's credentials, or the service account's key.
+    pub creds: Option<PathBuf>,
+    pub token: Option<PathBuf>,
+    pub state: Option<PathBuf>,
+    pub caldav_user: Option<String>,
+    /// Overrides the Google Calendar API's base URL, e.g. to test against a stand-in.
+    pub google_api_url: Option<String>,
+    /// Overrides where Google access tokens are refreshed.
+    pub google_token_url: Option<String>,
+    /// Overrides where batches of Google API requests are sent.
+    pub google_batch_url: Option<String>,
+
+    /// Where items that don't match any rule go.
+    pub default_calendar: String,
+    pub calendars: BTreeMap<String, CalendarConfig>,
+    /// Checked in order; the first one that matches an item decides where it goes.
+    pub rules: Vec<Rule>,
+    /// Where clocked time goes, if anywhere.
+    pub clock: Option<ClockConfig>,
+    pub filter: Filter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            todo_keywords: vec!["TODO".to_string(), "DOIN".to_string()],
+            done_keywords: vec!["DONE".to_string(), "CNCL".to_string()],
+            default_duration: "1:00".to_string(),
+            title: "TS: {title}".to_string(),
+            color: "8".to_string(),
+            tz: None,
+            dst: Dst::Compatible,
+            ignore_timestamps_in: vec![Context::Drawers, Context::Blocks],
+            strict: false,
+            port: None,
+            concurrency: 8,
+            retries: 5,
+            debounce_ms: 500,
+            full_sync_minutes: 60,
+
+            backend: Backend::Google,
+            auth: Auth::Loopback,
+            creds: None,
+            token: None,
+            state: None,
+            caldav_user: None,
+            google_api_url: None,
+            google_token_url: None,
+            google_batch_url: None,
+
+            default_calendar: "default".to_string(),
+            calendars: BTreeMap::new(),
+            rules: vec![],
+            clock: None,
+            filter: Filter::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarConfig {
+    /// The calendar's name (summary), or its collection URL for CalDAV.
+    pub name: String,
+    pub color: Option<String>,
+    pub title: Option<String>,
+    /// Reminders for events that don't set their own, like `10min, email 1d`. The calendar's
+    /// default reminders if not given.
+    pub reminders: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Defaults to [`Config::default_calendar`].
+    pub calendar: Option<String>,
+    /// Matches items with any of these tags, including inherited ones. A tag also matches its
+    /// `@` variants, so `w` matches `w@client`.
+    pub tags: Vec<String>,
+    /// Matches items with any of these categories (see [`AgendaItem::category`]).
+    pub categories: Vec<String>,
+    /// Matches items in files matching any of these globs, relative to the org directory.
+    pub files: Vec<String>,
+    /// Matches items with any of these TODO keywords.
+    pub todo: Vec<String>,
+    pub color: Option<String>,
+    pub title: Option<String>,
+
+    #[serde(skip)]
+    globs: GlobSet,
+}
+
+/// Which items have their timestamps synced. Clocked time is synced regardless.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Org matches (see [`Match`]). If there are any, only items matching one of them are synced.
+    pub include: Vec<String>,
+    /// Items matching any of these org matches aren't synced.
+    pub exclude: Vec<String>,
+    /// Whether to sync items in `:ARCHIVE:` trees.
+    pub archived: bool,
+    /// Whether to sync items under `COMMENT` headlines.
+    pub commented: bool,
+
+    #[serde(skip)]
+    include_matches: Vec<Match>,
+    #[serde(skip)]
+    exclude_matches: Vec<Match>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec!["noexport".to_string()],
+            archived: false,
+            commented: false,
+            include_matches: vec![],
+            exclude_matches: vec![],
+        }
+    }
+}
+
+impl Filter {
+    /// Whether `item`'s timestamps should be synced.
+    pub fn keeps(&self, item: &AgendaItem) -> bool {
+        (self.archived || !item.archived())
+            && (self.commented || !item.commented)
+            && (self.include_matches.is_empty()
+                || self.include_matches.iter().any(|m| m.matches(item)))
+            && !self.exclude_matches.iter().any(|m| m.matches(item))
+    }
+}
+
+/// Turns `CLOCK:` entries into past events, as a timesheet.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClockConfig {
+    /// A key of [`Config::calendars`].
+    pub calendar: String,
+    /// Event colors by category. Categories that aren't listed get one picked from their name.
+    #[serde(default)]
+    pub colors: BTreeMap<String, String>,
+    /// Whether to show running clocks, as ending now.
+    #[serde(default = "default_running")]
+    pub running: bool,
+}
+
+fn default_running() -> bool {
+    true
+}
+
+impl ClockConfig {
+    pub fn color(&self, category: &str) -> String {
+        if let Some(color) = self.colors.get(category) {
+            return color.clone();
+        }
+
+        let hash = u64::from_str_radix(&stable_hash([category.as_bytes()]), 16).unwrap_or(0);
+        (1 + hash % MAX_COLOR as u64).to_string()
+    }
+}
+
+/// Where an item should end up, and what it should look like.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// A key of [`Config::calendars`].
+    pub calendar: String,
+    pub title: String,
+    pub color: String,
+    pub reminders: Option<Vec<Reminder>>,
+}
+
+impl Config {
+    /// The config file we read if `--config` isn't given.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+
+        Some(base.join("org-tools").join("cal-sync.toml"))
+    }
+
+    /// Reads the config at `path`. If `required` is false, a missing file gives the defaults.
+    pub fn load(path: &Path, required: bool) -> Result<Self> {
+        if !required && !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read config {}", path.to_string_lossy()))?;
+        let config: Config = toml::from_str(&data)
+            .wrap_err_with(|| format!("Invalid config {}", path.to_string_lossy()))?;
+
+        Ok(config)
+    }
+
+    /// Checks everything serde can't, and compiles the rules' globs. Has to be called once
+    /// overrides are applied and before [`Config::route`].
+    pub fn validate(&mut self) -> Result<()> {
+        parse_duration(&self.default_duration).ok_or_else(|| {
+            eyre!(
+                "default_duration: invalid duration {:?}, expected e.g. 1:30 or 45min",
+                self.default_duration
+            )
+        })?;
+        check_color("color", &self.color)?;
+        check_title("title", &self.title)?;
+        if let Some(tz) = &self.tz {
+            TimeZone::get(tz).map_err(|e| eyre!("tz: invalid time zone {tz:?}: {e}"))?;
+        }
+        if self.port == Some(0) {
+            return Err(eyre!("port: can't be 0; leave it out to use any free port"));
+        }
+        if self.concurrency == 0 {
+            return Err(eyre!("concurrency: can't be 0"));
+        }
+        if self.full_sync_minutes == 0 {
+            return Err(eyre!("full_sync_minutes: can't be 0"));
+        }
+
+        if !self.calendars.contains_key(&self.default_calendar) {
+            return Err(eyre!(
+                "default_calendar: no calendar called {:?} in [calendars]",
+                self.default_calendar
+            ));
+        }
+        for (id, cal) in &self.calendars {
+            if let Some(color) = &cal.color {
+                check_color(&format!("calendars.{id}.color"), color)?;
+            }
+            if let Some(title) = &cal.title {
+                check_title(&format!("calendars.{id}.title"), title)?;
+            }
+            if let Some(reminders) = &cal.reminders {
+                parse_reminders(reminders).ok_or_else(|| {
+                    eyre!(
+                        "calendars.{id}.reminders: invalid reminders {reminders:?}, expected e.g. 10min, email 1d"
+                    )
+                })?;
+            }
+        }
+
+        if let Some(clock) = &self.clock {
+            if !self.calendars.contains_key(&clock.calendar) {
+                return Err(eyre!(
+                    "clock.calendar: no calendar called {:?} in [calendars]",
+                    clock.calendar
+                ));
+            }
+            for (category, color) in &clock.colors {
+                check_color(&format!("clock.colors.{category}"), color)?;
+            }
+        }
+
+        let parse = |key: &str, matches: &[String]| {
+            matches
+                .iter()
+                .enumerate()
+                .map(|(i, m)| m.parse().map_err(|e| eyre!("filter.{key}[{i}]: {e}")))
+                .collect::<Result<Vec<Match>>>()
+        };
+        self.filter.include_matches = parse("include", &self.filter.include)?;
+        self.filter.exclude_matches = parse("exclude", &self.filter.exclude)?;
+
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            if let Some(cal) = &rule.calendar {
+                if !self.calendars.contains_key(cal) {
+                    return Err(eyre!(
+                        "rules[{i}].calendar: no calendar called {cal:?} in [calendars]"
+                    ));
+                }
+            }
+            if let Some(color) = &rule.color {
+                check_color(&format!("rules[{i}].color"), color)?;
+            }
+            if let Some(title) = &rule.title {
+                check_title(&format!("rules[{i}].title"), title)?;
+            }
+
+            let mut globs = GlobSetBuilder::new();
+            for (j, g) in rule.files.iter().enumerate() {
+                globs.add(Glob::new(g).wrap_err_with(|| format!("rules[{i}].files[{j}]"))?);
+            }
+            rule.globs = globs
+                .build()
+                .wrap_err_with(|| format!("rules[{i}].files"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Where the Google APIs live, taking any overrides into account.
+    pub fn google_endpoints(&self) -> Endpoints {
+        let default = Endpoints::default();
+        Endpoints {
+            api: self.google_api_url.clone().unwrap_or(default.api),
+            auth: default.auth,
+            token: self.google_token_url.clone().unwrap_or(default.token),
+            batch: self.google_batch_url.clone().unwrap_or(default.batch),
+        }
+    }
+
+    /// How to retry failed requests.
+    pub fn retry(&self) -> Retry {
+        Retry {
+            attempts: self.retries + 1,
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+        }
+    }
+
+    /// The default event length in seconds.
+    pub fn default_duration(&self) -> i64 {
+        parse_duration(&self.default_duration).expect("validated")
+    }
+
+    /// The zone org timestamps are in by default.
+    pub fn time_zone(&self) -> TimeZone {
+        match &self.tz {
+            Some(tz) => TimeZone::get(tz).expect("validated"),
+            None => TimeZone::system(),
+        }
+    }
+
+    /// Decides which calendar `item` goes to. File globs are matched relative to `root`.
+    pub fn route(&self, item: &AgendaItem, root: &Path) -> Target {
+        let rule = self.rules.iter().find(|r| r.matches(item, root));
+
+        let calendar = rule
+            .and_then(|r| r.calendar.clone())
+            .unwrap_or_else(|| self.default_calendar.clone());
+        let cal = &self.calendars[&calendar];
+
+        let title = rule
+            .and_then(|r| r.title.as_ref())
+            .or(cal.title.as_ref())
+            .unwrap_or(&self.title);
+        let color = rule
+            .and_then(|r| r.color.clone())
+            .or_else(|| cal.color.clone())
+            .unwrap_or_else(|| self.color.clone());
+
+        Target {
+            title: render_title(title, item),
+            reminders: cal
+                .reminders
+                .as_deref()
+                .map(|r| parse_reminders(r).expect("validated")),
+            calendar,
+            color,
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, item: &AgendaItem, root: &Path) -> bool {
+        let path = item.path.strip_prefix(root).unwrap_or(&item.path);
+
+        (self.tags.is_empty() || item.tags.iter().any(|t| self.matches_tag(t)))
+            && (self.categories.is_empty() || self.categories.contains(&item.category))
+            && (self.files.is_empty() || self.globs.is_match(path))
+            && (self.todo.is_empty() || item.todo.as_ref().is_some_and(|t| self.todo.contains(t)))
+    }
+
+    /// Whether `tag` is one of our tags, or an `@` variant of one.
+    fn matches_tag(&self, tag: &str) -> bool {
+        let base = tag.split_once('@').map_or(tag, |(base, _)| base);
+        self.tags.iter().any(|t| t == tag || t == base)
+    }
+}
+
+fn check_color(key: &str, color: &str) -> Result<()> {
+    match color.parse::<u8>() {
+        Ok(1..=MAX_COLOR) => Ok(()),
+        _ => Err(eyre!(
+            "{key}: invalid color {color:?}, expected a number from 1 to {MAX_COLOR}"
+        )),
+    }
+}
+
+fn check_title(key: &str, title: &str) -> Result<()> {
+    let mut rest = title;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("{key}: unclosed {{ in {title:?}"))?;
+        let placeholder = &rest[start..start + end + 1];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(eyre!(
+                "{key}: unknown placeholder {placeholder} in {title:?}, expected one of {}",
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(())
+}
+
+/// Fills in a title template, in one pass so that a value that looks like a placeholder (a
+/// headline called `{file}`, say) is left as it is.
+fn render_title(template: &str, item: &AgendaItem) -> String {
+    let mut title = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        title.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match &rest[..=end] {
+            "{title}" => title.push_str(&item.name),
+            "{todo}" => title.push_str(item.todo.as_deref().unwrap_or_default()),
+            "{priority}" => {
+                if let Some(p) = &item.priority {
+                    title.push_str(&format!("[#{p}]"));
+                }
+            }
+            "{category}" => title.push_str(&item.category),
+            "{file}" => {
+                if let Some(stem) = item.path.file_stem() {
+                    title.push_str(&stem.to_string_lossy());
+                }
+            }
+            // Unknown placeholders are rejected when the config is loaded.
+            other => title.push_str(other),
+        }
+        rest = &rest[end + 1..];
+    }
+    title.push_str(rest);
+
+    // Empty placeholders would leave doubled-up spaces behind.
+    title.split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,180 @@
+//! Org's tag and property matches, as in `org-tags-view`, for deciding which items to sync.
+//!
+//! `+work-boss|TODO="NEXT"` matches items tagged `work` but not `boss`, and items whose TODO
+//! keyword is `NEXT`. Properties are compared with `=`, `<>`, `<`, `<=`, `>` or `>=`, as numbers
+//! if both sides are numbers and as strings otherwise, so `PRIORITY<="B"` matches priorities `A`
+//! and `B`. Besides the headline's own properties there are `TODO`, `PRIORITY`, `CATEGORY` and
+//! `LEVEL`.
+
+use std::{borrow::Cow, cmp::Ordering, str::FromStr};
+
+use crate::org::AgendaItem;
+
+/// What org takes the priority of headlines without a cookie to be.
+const DEFAULT_PRIORITY: &str = "B";
+
+/// A parsed match. Matches an item if any of its alternatives does.
+#[derive(Debug, Clone)]
+pub struct Match {
+    alternatives: Vec<Vec<Term>>,
+}
+
+/// One `+` or `-` part of an alternative.
+#[derive(Debug, Clone)]
+struct Term {
+    negated: bool,
+    cond: Cond,
+}
+
+#[derive(Debug, Clone)]
+enum Cond {
+    Tag(String),
+    Property { name: String, op: Op, value: String },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Longest first, so `<=` isn't read as `<`.
+const OPS: [(&str, Op); 6] = [
+    ("<>", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+impl FromStr for Match {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let alternatives = s
+            .split('|')
+            .map(|alt| parse_alternative(alt.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if alternatives.iter().any(Vec::is_empty) {
+            return Err(format!("empty alternative in {s:?}"));
+        }
+
+        Ok(Self { alternatives })
+    }
+}
+
+fn parse_alternative(s: &str) -> Result<Vec<Term>, String> {
+    let mut terms = vec![];
+    let mut rest = s;
+    while !rest.is_empty() {
+        let (negated, r) = if let Some(r) = rest.strip_prefix('-') {
+            (true, r)
+        } else {
+            (false, rest.strip_prefix(['+', '&']).unwrap_or(rest))
+        };
+
+        let len = r.find(|c| !is_word(c)).unwrap_or(r.len());
+        if len == 0 {
+            return Err(format!("expected a tag or property at {r:?}"));
+        }
+        let (name, r) = r.split_at(len);
+
+        let Some((op_str, op)) = OPS.iter().find(|(o, _)| r.starts_with(o)) else {
+            terms.push(Term {
+                negated,
+                cond: Cond::Tag(name.to_string()),
+            });
+            rest = r;
+            continue;
+        };
+
+        let r = &r[op_str.len()..];
+        let (value, r) = if let Some(quoted) = r.strip_prefix('"') {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| format!("unclosed \" in {s:?}"))?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = r
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(r.len());
+            if end == 0 {
+                return Err(format!(
+                    "expected a number or a quoted string after {name}{op_str}"
+                ));
+            }
+            r.split_at(end)
+        };
+
+        terms.push(Term {
+            negated,
+            cond: Cond::Property {
+                name: name.to_ascii_uppercase(),
+                op,
+                value: value.to_string(),
+            },
+        });
+        rest = r;
+    }
+
+    Ok(terms)
+}
+
+/// Characters tags and property names are made of.
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '@' | '#' | '%')
+}
+
+impl Match {
+    pub fn matches(&self, item: &AgendaItem) -> bool {
+        self.alternatives
+            .iter()
+            .any(|alt| alt.iter().all(|t| t.cond.holds(item) != t.negated))
+    }
+}
+
+impl Cond {
+    fn holds(&self, item: &AgendaItem) -> bool {
+        match self {
+            Cond::Tag(tag) => item.tags.iter().any(|t| t == tag),
+            Cond::Property { name, op, value } => {
+                let actual = property(item, name);
+                let ord = match (actual.parse::<f64>(), value.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.partial_cmp(&b),
+                    _ => Some(actual.as_ref().cmp(value.as_str())),
+                };
+                let Some(ord) = ord else {
+                    return false;
+                };
+
+                match op {
+                    Op::Eq => ord == Ordering::Equal,
+                    Op::Ne => ord != Ordering::Equal,
+                    Op::Lt => ord == Ordering::Less,
+                    Op::Le => ord != Ordering::Greater,
+                    Op::Gt => ord == Ordering::Greater,
+                    Op::Ge => ord != Ordering::Less,
+                }
+            }
+        }
+    }
+}
+
+/// The value of property `name` on `item`. Like in org, missing properties are empty, and
+/// headlines without a priority cookie have the default priority.
+fn property<'a>(item: &'a AgendaItem, name: &str) -> Cow<'a, str> {
+    let value = match name {
+        "TODO" => item.todo.as_deref(),
+        "PRIORITY" => Some(item.priority.as_deref().unwrap_or(DEFAULT_PRIORITY)),
+        "CATEGORY" => Some(item.category.as_str()),
+        "LEVEL" => return Cow::Owned(item.outline.len().to_string()),
+        _ => item.properties.get(name).map(String::as_str),
+    };
+
+    Cow::Borrowed(value.unwrap_or_default())
+}
@@ -1,62 +1,273 @@
-use std::{fs, path::PathBuf};
-
-use axum::{
-    extract::{Query, State},
-    routing::get,
-    Router,
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
 };
+
 use color_eyre::{
-    eyre::{eyre, ContextCompat, OptionExt},
-    Result,
+    eyre::{eyre, ContextCompat, OptionExt, WrapErr},
+    Report, Result,
 };
-use futures::future::join_all;
 use google_calendar::{
-    types::{Event, MinAccessRole, OrderBy, SendUpdates},
-    AccessToken, Client,
+    types::{
+        Event, EventReminder, ExtendedProperties, MinAccessRole, OrderBy, Reminders, SendUpdates,
+    },
+    Client, ClientError,
 };
-use serde::Deserialize;
-use tokio::sync::{
-    mpsc,
-    oneshot::{self},
+use tracing::warn;
+
+use crate::{
+    backend::{parse_retry_after, ApiError, CalendarBackend, RemoteEvent, SyncEvent},
+    batch::{self, Part},
+    config::Auth,
+    oauth::{self, ClientCreds},
+    org::{Dateish, Reminder, ReminderMethod},
+    token::{self, Token, TokenStore},
 };
-use tracing::{debug, info};
 
-use crate::org::AgendaItem;
+/// Where the Google APIs live. Only ever changed to test against a stand-in.
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    /// Base URL of the Calendar v3 API.
+    pub api: String,
+    /// Where users are asked for consent.
+    pub auth: String,
+    /// Where access tokens are refreshed.
+    pub token: String,
+    /// Where batches of API requests go.
+    pub batch: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            api: "https://www.googleapis.com/calendar/v3".to_string(),
+            auth: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+            token: "https://oauth2.googleapis.com/token".to_string(),
+            batch: "https://www.googleapis.com/batch/calendar/v3".to_string(),
+        }
+    }
+}
+
+/// Description given to every event we managed before we started keying them.
+const GENERATED_DESC: &str = "cal_sync.py marker description";
+/// Private extended property holding the org key an event was generated from.
+const KEY_PROP: &str = "cal_sync_key";
+const MAX_REMINDERS: usize = 5;
+const MAX_REMINDER_MINUTES: i64 = 4 * 7 * 24 * 60;
+/// Private extended property holding a hash of the event's contents at the time we wrote it.
+const HASH_PROP: &str = "cal_sync_hash";
+
+/// An authenticated connection to Google.
+#[derive(Clone)]
+pub struct Session {
+    client: Client,
+    /// Batches go around `client`, so they need the token themselves.
+    access_token: String,
+    /// When `access_token` expires, in seconds since the epoch, if we know.
+    expires_at: Option<i64>,
+    endpoints: Endpoints,
+    http: reqwest::Client,
+}
 
-const PORT: u16 = 8081;
-const TIMEOUT: u64 = 90;
+impl Session {
+    pub fn new(mut client: Client, access_token: String, endpoints: Endpoints) -> Self {
+        client.with_host_override(&endpoints.api);
+
+        Self {
+            client,
+            access_token,
+            expires_at: None,
+            endpoints,
+            http: reqwest::Client::new(),
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-struct Credentials {
-    installed: CredentialsInner,
+    /// Whether the access token is about to expire, so that callers that stay running know to
+    /// get a new session with [`refresh`].
+    pub fn expires_soon(&self) -> bool {
+        self.expires_at.is_some_and(token::expires_soon)
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct CredentialsInner {
-    client_id: String,
-    client_secret: String,
+/// Syncs to a single Google calendar.
+pub struct GoogleBackend {
+    session: Session,
+    cal_id: String,
 }
 
-pub async fn sync(client: Client, events: Vec<AgendaItem>, calendar_summary: &str) -> Result<()> {
-    // First, delete all the events with the matching description.
-    const GENERATED_DESC: &str = "cal_sync.py marker description";
-
-    // First, let's get the calendar ID.
-    let cal = client
-        .calendar_list()
-        .list_all(MinAccessRole::Noop, false, false)
-        .await?
-        .body
-        .into_iter()
-        .find(|c| c.summary == calendar_summary)
-        .wrap_err(format!("Couldn't find calendar {}", calendar_summary))?;
-
-    // Next, find all events in this calendar with the matching description.
-    let dels = join_all(
-        client
+impl GoogleBackend {
+    /// Looks up the calendar with the given summary (name).
+    pub async fn new(session: Session, calendar_summary: &str) -> Result<Self> {
+        let cal = session
+            .client
+            .calendar_list()
+            .list_all(MinAccessRole::Noop, false, false)
+            .await
+            .map_err(|e| api_error("calendarList.list".to_string(), e))?
+            .body
+            .into_iter()
+            .find(|c| c.summary == calendar_summary)
+            .wrap_err(format!("Couldn't find calendar {}", calendar_summary))?;
+
+        Ok(Self {
+            session,
+            cal_id: cal.id,
+        })
+    }
+
+    /// Every event that overlaps `start..end`, with repeating ones expanded into their
+    /// occurrences, except the ones we manage.
+    pub async fn foreign_events(
+        &self,
+        start: &jiff::Timestamp,
+        end: &jiff::Timestamp,
+    ) -> Result<Vec<Event>> {
+        let evs = self
+            .session
+            .client
             .events()
             .list_all(
-                &cal.id,
+                &self.cal_id,
+                "",
+                0,
+                OrderBy::StartTime,
+                &[],
+                "",
+                &[],
+                false,
+                false,
+                true,
+                &end.to_string(),
+                &start.to_string(),
+                "",
+                "",
+            )
+            .await
+            .map_err(|e| api_error(format!("events.list {}", self.cal_id), e))?
+            .body
+            .into_iter()
+            .filter(|ev| !is_managed(ev) && ev.status != "cancelled")
+            .collect();
+
+        Ok(evs)
+    }
+
+    /// The path of this calendar's events (or of the event `id`), as batches want it.
+    fn events_path(&self, id: Option<&str>) -> String {
+        let base = reqwest::Url::parse(&self.session.endpoints.api)
+            .map(|u| u.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        let mut path = format!("{base}/calendars/{}/events", encode_segment(&self.cal_id));
+        if let Some(id) = id {
+            path.push('/');
+            path.push_str(&encode_segment(id));
+        }
+
+        path
+    }
+
+    /// Sends `parts` as a batch, returning the body of each part's response.
+    async fn batch(&self, parts: Vec<Part>) -> Vec<Result<String>> {
+        let responses = batch::send(
+            &self.session.http,
+            &self.session.endpoints.batch,
+            &self.session.access_token,
+            &parts,
+        )
+        .await;
+
+        match responses {
+            Ok(responses) => responses
+                .into_iter()
+                .zip(&parts)
+                .map(|(resp, part)| {
+                    let request = format!("{} {}", part.method, part.path);
+                    let resp =
+                        resp.ok_or_else(|| eyre!("{request}: missing from batch response"))?;
+                    if (200..300).contains(&resp.status) {
+                        return Ok(resp.body);
+                    }
+
+                    Err(ApiError {
+                        request,
+                        status: resp.status,
+                        retry_after: resp.header("retry-after").and_then(parse_retry_after),
+                        message: resp.body,
+                    }
+                    .into())
+                })
+                .collect(),
+            // If the whole batch failed, so did every part of it.
+            Err(e) => parts
+                .iter()
+                .map(|_| match e.downcast_ref::<ApiError>() {
+                    Some(api) => Err(api.clone().into()),
+                    None => Err(eyre!("{e:#}")),
+                })
+                .collect(),
+        }
+    }
+
+    /// Google takes at most five reminders, none of them more than four weeks ahead.
+    fn to_reminders(reminders: &[Reminder]) -> Vec<EventReminder> {
+        let mut reminders: Vec<_> = reminders
+            .iter()
+            .map(|r| (r.minutes.clamp(0, MAX_REMINDER_MINUTES), r.method))
+            .collect();
+        reminders.sort();
+        reminders.dedup();
+
+        reminders
+            .into_iter()
+            .take(MAX_REMINDERS)
+            .map(|(minutes, method)| EventReminder {
+                method: match method {
+                    ReminderMethod::Popup => "popup".to_string(),
+                    ReminderMethod::Email => "email".to_string(),
+                },
+                minutes,
+            })
+            .collect()
+    }
+
+    fn to_event(ev: &SyncEvent) -> Event {
+        Event {
+            summary: ev.summary.clone(),
+            description: ev.description.clone(),
+            location: ev.location.clone().unwrap_or_default(),
+            start: Some(ev.start.clone().into_gcal()),
+            end: ev.end.clone().map(|e| e.into_gcal()),
+            recurrence: ev.recurrence.iter().cloned().collect(),
+            color_id: ev.color.clone(),
+            reminders: Some(Reminders {
+                use_default: ev.reminders.is_none(),
+                overrides: ev
+                    .reminders
+                    .iter()
+                    .flat_map(|rs| Self::to_reminders(rs))
+                    .collect(),
+            }),
+            extended_properties: Some(ExtendedProperties {
+                private: Some(HashMap::from([
+                    (KEY_PROP.to_string(), ev.key.clone()),
+                    (HASH_PROP.to_string(), ev.hash()),
+                ])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+impl CalendarBackend for GoogleBackend {
+    async fn list(&self) -> Result<Vec<RemoteEvent>> {
+        // Find all events in this calendar that we manage.
+        let evs = self
+            .session
+            .client
+            .events()
+            .list_all(
+                &self.cal_id,
                 "",
                 0,
                 OrderBy::Noop,
@@ -71,220 +282,321 @@ pub async fn sync(client: Client, events: Vec<AgendaItem>, calendar_summary: &st
                 "",
                 "",
             )
-            .await?
+            .await
+            .map_err(|e| api_error(format!("events.list {}", self.cal_id), e))?
             .body
             .into_iter()
-            .filter(|ev| ev.description == GENERATED_DESC)
-            .map(|ev| {
-                let cal_id = cal.id.clone();
-                let client = client.clone();
+            .filter_map(|ev| {
+                if !is_managed(&ev) {
+                    return None;
+                }
+                let private = ev
+                    .extended_properties
+                    .and_then(|p| p.private)
+                    .unwrap_or_default();
+
+                Some(RemoteEvent {
+                    id: ev.id,
+                    summary: ev.summary,
+                    start: ev.start.as_ref().and_then(Dateish::from_gcal),
+                    end: ev.end.as_ref().and_then(Dateish::from_gcal),
+                    key: private.get(KEY_PROP).cloned(),
+                    hash: private.get(HASH_PROP).cloned(),
+                })
+            })
+            .collect();
 
-                async move {
-                    let ev_id = ev.id;
+        Ok(evs)
+    }
 
-                    debug!("del {}", ev.summary);
+    async fn insert(&self, ev: &SyncEvent) -> Result<String> {
+        let resp = self
+            .session
+            .client
+            .events()
+            .insert(
+                &self.cal_id,
+                0,
+                0,
+                false,
+                SendUpdates::Noop,
+                false,
+                &Self::to_event(ev),
+            )
+            .await
+            .map_err(|e| api_error(format!("events.insert {}", ev.summary), e))?;
 
-                    client
-                        .events()
-                        .delete(&cal_id, &ev_id, false, SendUpdates::Noop)
-                        .await
-                }
-            }),
-    )
-    .await;
-
-    // Await all delete tasks
-    let mut deleted_evs = 0;
-    for res in dels {
-        let _ = res?;
-        deleted_evs += 1;
+        Ok(resp.body.id)
     }
-    info!("Deleted: {deleted_evs}");
 
-    // Now, let's add all of our org tasks
-    let adds = join_all(
-        events
+    async fn update(&self, id: &str, ev: &SyncEvent) -> Result<()> {
+        self.session
+            .client
+            .events()
+            .patch(
+                &self.cal_id,
+                id,
+                0,
+                0,
+                false,
+                SendUpdates::Noop,
+                false,
+                &Self::to_event(ev),
+            )
+            .await
+            .map_err(|e| api_error(format!("events.patch {id}"), e))?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.session
+            .client
+            .events()
+            .delete(&self.cal_id, id, false, SendUpdates::Noop)
+            .await
+            .map_err(|e| api_error(format!("events.delete {id}"), e))?;
+
+        Ok(())
+    }
+
+    fn batch_size(&self) -> usize {
+        batch::MAX_PARTS
+    }
+
+    async fn insert_batch(&self, evs: &[&SyncEvent]) -> Vec<Result<String>> {
+        let parts = evs
+            .iter()
+            .map(|ev| Part {
+                method: "POST",
+                path: self.events_path(None),
+                body: Some(serde_json::to_value(Self::to_event(ev)).expect("Events serialize")),
+            })
+            .collect();
+
+        self.batch(parts)
+            .await
             .into_iter()
-            .flat_map(|new_ev| {
-                new_ev
-                    .timestamps
-                    .into_iter()
-                    .map(move |x| (x, new_ev.name.clone()))
+            .map(|res| {
+                let created: serde_json::Value = serde_json::from_str(&res?)?;
+                created["id"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_eyre("No id for inserted event")
+            })
+            .collect()
+    }
+
+    async fn update_batch(&self, evs: &[(&str, &SyncEvent)]) -> Vec<Result<()>> {
+        let parts = evs
+            .iter()
+            .map(|(id, ev)| Part {
+                method: "PATCH",
+                path: self.events_path(Some(id)),
+                body: Some(serde_json::to_value(Self::to_event(ev)).expect("Events serialize")),
             })
-            .map(|(s, name)| {
-                let client = client.clone();
-                let cal_id = cal.id.clone();
-
-                let (start, end, rep) = s.into_gcal();
-
-                async move {
-                    let e = Event {
-                        summary: format!("TS: {name}"),
-                        description: GENERATED_DESC.to_string(),
-                        start: Some(start),
-                        end,
-                        recurrence: rep.map(|r| vec![r]).unwrap_or_else(Vec::new),
-                        color_id: "8".to_string(),
-                        ..Default::default()
-                    };
-
-                    client
-                        .events()
-                        .insert(&cal_id, 0, 0, false, SendUpdates::Noop, false, &e)
-                        .await
+            .collect();
+
+        self.batch(parts)
+            .await
+            .into_iter()
+            .map(|res| res.map(|_| ()))
+            .collect()
+    }
+
+    async fn delete_batch(&self, ids: &[&str]) -> Vec<Result<()>> {
+        let parts = ids
+            .iter()
+            .map(|id| Part {
+                method: "DELETE",
+                path: self.events_path(Some(id)),
+                body: None,
+            })
+            .collect();
+
+        self.batch(parts)
+            .await
+            .into_iter()
+            .map(|res| match res {
+                // If it's already gone, that's fine too.
+                Err(e)
+                    if e.downcast_ref::<ApiError>()
+                        .is_some_and(|e| matches!(e.status, 404 | 410)) =>
+                {
+                    Ok(())
                 }
-            }),
-    )
-    .await;
-
-    // Await all insert tasks
-    let mut inserted_evs = 0;
-    for res in adds {
-        let r = res?;
-        debug!("ins {}", r.body.summary);
-        inserted_evs += 1;
+                res => res.map(|_| ()),
+            })
+            .collect()
     }
-    info!("Inserted: {inserted_evs}");
+}
 
-    println!("-{deleted_evs} +{inserted_evs}");
+/// Percent-encodes a path segment. Calendar ids are usually email addresses, and can contain
+/// `#`.
+fn encode_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Turns the client's HTTP errors into [`ApiError`]s, so that they can be retried.
+fn api_error(request: String, e: ClientError) -> Report {
+    match e {
+        ClientError::HttpError {
+            status,
+            headers,
+            error,
+        } => ApiError {
+            request,
+            status: status.as_u16(),
+            message: error,
+            retry_after: headers
+                .get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after),
+        }
+        .into(),
+        e => Report::new(e).wrap_err(request),
+    }
+}
 
-    Ok(())
+/// Whether we wrote `ev`. Old versions marked events with a special description; now they carry
+/// their key in an extended property.
+fn is_managed(ev: &Event) -> bool {
+    ev.description == GENERATED_DESC
+        || ev
+            .extended_properties
+            .as_ref()
+            .and_then(|p| p.private.as_ref())
+            .is_some_and(|p| p.contains_key(KEY_PROP))
 }
 
-async fn try_refresh_client(
-    client_id: String,
-    client_secret: String,
-    redirect_uri: &str,
-    token_path: PathBuf,
-) -> Result<Client> {
-    if !token_path.exists() {
-        return Err(eyre!("w/e"));
+/// Connects to Google. Users are asked for consent with `auth` if there isn't a token in `store`
+/// to use or refresh; service accounts sign in with the key at `creds_path` every time.
+pub async fn get_client(
+    auth: Auth,
+    creds_path: PathBuf,
+    store: Option<TokenStore>,
+    port: Option<u16>,
+    endpoints: &Endpoints,
+) -> Result<Session> {
+    let http = reqwest::Client::new();
+
+    if auth == Auth::ServiceAccount {
+        return service_session(&http, endpoints, &creds_path).await;
     }
-    let data = fs::read_to_string(&token_path)?;
-    let tok = serde_json::from_str::<'_, AccessToken>(&data)?;
 
-    let c = Client::new(
-        client_id,
-        client_secret,
-        redirect_uri,
-        tok.access_token,
-        tok.refresh_token,
-    );
+    let store = store.ok_or_eyre("--token is required for google")?;
+    let creds = ClientCreds::load(&creds_path)?;
+
+    let tok = match store.load()? {
+        Some(tok) if !tok.expires_soon() => return Ok(session(&creds, &tok, endpoints)),
+        // We refresh by hand rather than through the client so that it goes to `endpoints.token`.
+        Some(tok) => match oauth::refresh(&http, endpoints, &creds, &tok.refresh_token).await {
+            Ok(resp) => Some(Token::from_response(&resp, Some(&tok.refresh_token))?),
+            Err(e) => {
+                warn!("Couldn't refresh the Google token, signing in again: {e:#}");
+                None
+            }
+        },
+        None => None,
+    };
+    if let Some(tok) = tok {
+        store.save(&tok)?;
+        return Ok(session(&creds, &tok, endpoints));
+    }
 
-    let new_tok = c.refresh_access_token().await?;
+    let tok = match auth {
+        Auth::Loopback => {
+            oauth::loopback(&http, endpoints, &creds, port, |url| {
+                eprintln!("Sign in to Google at {url}");
+                // Only a convenience; the link is printed for machines we can't open it on.
+                let _ = open::that(url);
+            })
+            .await?
+        }
+        Auth::Manual => {
+            oauth::manual(&http, endpoints, &creds, |url| {
+                eprintln!("Sign in to Google at {url}");
+                eprintln!("Then paste the address your browser ends up at (it won't load):");
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line)?;
+                Ok(line)
+            })
+            .await?
+        }
+        Auth::ServiceAccount => unreachable!("handled above"),
+    };
 
-    // Write our new token
-    let out = serde_json::to_string_pretty(&new_tok)?;
-    fs::write(token_path, out)?;
+    let tok = Token::from_response(&tok, None)?;
+    store.save(&tok)?;
 
-    Ok(c)
+    Ok(session(&creds, &tok, endpoints))
 }
 
-pub async fn get_client(creds_path: PathBuf, token_path: PathBuf) -> Result<Client> {
-    let scopes: [String; 2] = [
-        "https://www.googleapis.com/auth/calendar.readonly".to_string(),
-        "https://www.googleapis.com/auth/calendar.events".to_string(),
-    ];
-    let creds = fs::read_to_string(creds_path)?;
-    let Credentials {
-        installed: CredentialsInner {
-            client_id,
-            client_secret,
-        },
-    } = serde_json::from_str::<'_, Credentials>(&creds)?;
-
-    let redirect_uri = format!("http://localhost:{}", PORT);
-
-    if let Ok(c) = try_refresh_client(
-        client_id.clone(),
-        client_secret.clone(),
-        &redirect_uri,
-        token_path.clone(),
-    )
-    .await
-    {
-        Ok(c)
-    } else {
-        let mut c = Client::new(client_id, client_secret, &redirect_uri, "", "");
-
-        let (resp_tx, resp_rx) = oneshot::channel();
-
-        tokio::spawn(spawn_oauth_listener(resp_tx));
-
-        // Get the URL to request consent from the user.
-        // You can optionally pass in scopes. If none are provided, then the
-        // resulting URL will not have any scopes.
-        let user_consent_url = c.user_consent_url(&scopes);
-        open::that(user_consent_url)?;
-
-        // In your redirect URL capture the code sent and our state.
-        // Send it along to the request for the token.
-        let OAuthResp { code, state } = resp_rx.await?.ok_or_eyre("Timed out.")?;
-        let access_token = c.get_access_token(&code, &state).await?;
-
-        // Write the access token back
-        let new_state = serde_json::to_string_pretty(&access_token)?;
-        fs::write(token_path, new_state)?;
-
-        Ok(c)
+/// Gets a session with a fresh access token, for callers that stay running for longer than one
+/// lasts; [`get_client`] only makes sure it's good for a few minutes. The token in `store` is
+/// refreshed if it's about to expire, and service accounts sign in again. Nobody is asked for
+/// consent, so this fails if the token can't be refreshed.
+pub async fn refresh(
+    auth: Auth,
+    creds_path: &Path,
+    store: Option<&TokenStore>,
+    endpoints: &Endpoints,
+) -> Result<Session> {
+    let http = reqwest::Client::new();
+
+    if auth == Auth::ServiceAccount {
+        return service_session(&http, endpoints, creds_path).await;
     }
-}
 
-#[derive(Debug, Clone, Deserialize)]
-struct OAuthResp {
-    code: String,
-    state: String,
-}
+    let store = store.ok_or_eyre("--token is required for google")?;
+    let creds = ClientCreds::load(creds_path)?;
+    let mut tok = store.load()?.ok_or_eyre("No Google token to refresh")?;
+    if tok.expires_soon() {
+        let resp = oauth::refresh(&http, endpoints, &creds, &tok.refresh_token)
+            .await
+            .wrap_err("Couldn't refresh the Google token")?;
+        tok = Token::from_response(&resp, Some(&tok.refresh_token))?;
+        store.save(&tok)?;
+    }
 
-async fn spawn_oauth_listener(tx: oneshot::Sender<Option<OAuthResp>>) -> Result<()> {
-    // Creating an OAuth listener requires that we have a channel to send the OAuth response from.
-    // We also create another channel that we use to kill this server once we've received the
-    // correct response.
-    // We use mpsc and not oneshot because there's no way of telling axum that our route is only
-    // ever called once.
-    let (inner_tx, mut inner_rx) = mpsc::channel::<OAuthResp>(1);
-
-    // One sends information from
-    let app = Router::new()
-        .route(
-            "/",
-            get(
-                move |State(state): State<mpsc::Sender<OAuthResp>>, resp: Query<OAuthResp>| async move {
-                    // Send the resp over the channel
-                    state
-                        .send(resp.0)
-                        .await
-                        .expect("Couldn't send OAuthResp in server");
-
-                    // Reply with text
-                    "Successfully received OAuth response. You can close this window."
-                },
-            ),
-        )
-        .with_state(inner_tx);
-
-    // run our app with hyper, listening globally on port PORT
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", PORT))
-        .await
-        .unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            let value = async { inner_rx.recv().await.expect("Couldn't get inner_rx value") };
-            let timer = tokio::time::sleep(std::time::Duration::from_secs(TIMEOUT));
-
-            tokio::select! {
-                _ = timer => {
-                    tx.send(None).expect("Couldn't send");
-                },
-                v = value => {
+    Ok(session(&creds, &tok, endpoints))
+}
 
-                    tx.send(Some(v)).expect("Couldn't send");
+/// A session using `tok`.
+fn session(creds: &ClientCreds, tok: &Token, endpoints: &Endpoints) -> Session {
+    let c = Client::new(
+        creds.client_id.clone(),
+        creds.client_secret.clone(),
+        "",
+        &tok.access_token,
+        &tok.refresh_token,
+    );
 
-                },
-            }
-        })
-        .await
-        .unwrap();
+    Session {
+        expires_at: tok.expires_at,
+        ..Session::new(c, tok.access_token.clone(), endpoints.clone())
+    }
+}
 
-    Ok(())
+/// Signs in as the service account whose key is at `key_path`.
+async fn service_session(
+    http: &reqwest::Client,
+    endpoints: &Endpoints,
+    key_path: &Path,
+) -> Result<Session> {
+    let (access_token, expires_at) = oauth::service_account(http, endpoints, key_path).await?;
+    // There's nothing to refresh with, so the client doesn't need the rest.
+    let c = Client::new("", "", "", &access_token, "");
+
+    Ok(Session {
+        expires_at,
+        ..Session::new(c, access_token, endpoints.clone())
+    })
 }
@@ -0,0 +1,330 @@
+//! Just enough iCalendar (RFC 5545) to write our events out and read them back in.
+
+use std::{collections::BTreeMap, path::Path};
+
+use color_eyre::Result;
+
+use jiff::{
+    tz::{Offset, TimeZone},
+    Timestamp, ToSpan, Zoned,
+};
+
+use crate::{atomic, backend::SyncEvent, org::Dateish};
+
+const PRODID: &str = "-//org-tools//cal-sync//EN";
+
+/// Custom property holding [`SyncEvent::key`].
+pub const KEY_PROP: &str = "X-CAL-SYNC-KEY";
+/// Custom property holding [`SyncEvent::hash`].
+pub const HASH_PROP: &str = "X-CAL-SYNC-HASH";
+
+/// How many years past the last event we describe time zone transitions for, so that
+/// repeating events stay in the right zone for a while.
+const TZ_YEARS_AHEAD: i16 = 5;
+
+/// Renders a VCALENDAR containing a VEVENT for each event, plus a VTIMEZONE for each time zone
+/// the events are in.
+pub fn calendar<'a>(events: impl IntoIterator<Item = &'a SyncEvent>) -> String {
+    let events: Vec<&SyncEvent> = events.into_iter().collect();
+
+    let mut out = String::new();
+    line(&mut out, "BEGIN:VCALENDAR");
+    line(&mut out, "VERSION:2.0");
+    line(&mut out, &format!("PRODID:{PRODID}"));
+    line(&mut out, "CALSCALE:GREGORIAN");
+
+    // Figure out which years each zone needs to be described for.
+    let mut zones: BTreeMap<String, (TimeZone, i16, i16)> = BTreeMap::new();
+    for d in events
+        .iter()
+        .flat_map(|ev| std::iter::once(&ev.start).chain(&ev.end))
+    {
+        let Dateish::Precise(zoned) = d else {
+            continue;
+        };
+        let Some(name) = zoned.time_zone().iana_name() else {
+            continue;
+        };
+
+        let (_, lo, hi) = zones
+            .entry(name.to_string())
+            .or_insert_with(|| (zoned.time_zone().clone(), zoned.year(), zoned.year()));
+        *lo = (*lo).min(zoned.year());
+        *hi = (*hi).max(zoned.year());
+    }
+    for (name, (tz, lo, hi)) in &zones {
+        vtimezone(&mut out, name, tz, *lo, hi.saturating_add(TZ_YEARS_AHEAD));
+    }
+
+    let stamp = utc(&Timestamp::now());
+    for ev in events {
+        line(&mut out, "BEGIN:VEVENT");
+        line(&mut out, &format!("UID:{}", uid(&ev.key)));
+        line(&mut out, &format!("DTSTAMP:{stamp}"));
+        line(&mut out, &date_prop("DTSTART", &ev.start));
+        if let Some(end) = &ev.end {
+            line(&mut out, &date_prop("DTEND", end));
+        }
+        if let Some(rrule) = &ev.recurrence {
+            line(&mut out, rrule);
+        }
+        line(&mut out, &format!("SUMMARY:{}", escape(&ev.summary)));
+        line(
+            &mut out,
+            &format!("DESCRIPTION:{}", escape(&ev.description)),
+        );
+        if let Some(location) = &ev.location {
+            line(&mut out, &format!("LOCATION:{}", escape(location)));
+        }
+        line(&mut out, &format!("{KEY_PROP}:{}", escape(&ev.key)));
+        line(&mut out, &format!("{HASH_PROP}:{}", ev.hash()));
+        // An EMAIL alarm needs an ATTENDEE to send to, and we don't know anyone's address, so
+        // every reminder is shown by the calendar app.
+        for r in ev.reminders.iter().flatten() {
+            line(&mut out, "BEGIN:VALARM");
+            line(&mut out, "ACTION:DISPLAY");
+            line(&mut out, &format!("TRIGGER:-PT{}M", r.minutes));
+            line(&mut out, &format!("DESCRIPTION:{}", escape(&ev.summary)));
+            line(&mut out, "END:VALARM");
+        }
+        line(&mut out, "END:VEVENT");
+    }
+
+    line(&mut out, "END:VCALENDAR");
+    out
+}
+
+/// Writes a calendar containing `events` to `path`, returning how many there were.
+///
+/// The file is replaced atomically, so anything serving it never sees a half-written feed.
+pub fn write(path: &Path, events: &[SyncEvent]) -> Result<usize> {
+    atomic::write(path, calendar(events).as_bytes(), 0o644)?;
+
+    Ok(events.len())
+}
+
+/// Turns an org key into something usable as a globally unique UID.
+pub fn uid(key: &str) -> String {
+    format!("{key}@cal-sync")
+}
+
+fn date_prop(name: &str, d: &Dateish) -> String {
+    match d {
+        Dateish::AllDay(date) => format!("{name};VALUE=DATE:{}", date.strftime("%Y%m%d")),
+        Dateish::Precise(zoned) => match zoned.time_zone().iana_name() {
+            Some(tzid) => format!("{name};TZID={tzid}:{}", local(zoned)),
+            // Without a name there's no VTIMEZONE to refer to, so fall back to UTC.
+            None => format!("{name}:{}", utc(&zoned.timestamp())),
+        },
+    }
+}
+
+fn local(zoned: &Zoned) -> String {
+    zoned.strftime("%Y%m%dT%H%M%S").to_string()
+}
+
+fn utc(ts: &Timestamp) -> String {
+    ts.to_zoned(TimeZone::UTC)
+        .strftime("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// Writes a VTIMEZONE describing every offset transition `tz` makes between the start of
+/// year `from` and the end of year `to`.
+///
+/// We list each transition as its own observance rather than trying to recover the rule that
+/// generated it, which RFC 5545 allows and every client we've tried handles fine.
+fn vtimezone(out: &mut String, name: &str, tz: &TimeZone, from: i16, to: i16) {
+    let Ok(start) = jiff::civil::date(from, 1, 1).to_zoned(TimeZone::UTC) else {
+        return;
+    };
+    let Ok(end) = jiff::civil::date(to, 12, 31).to_zoned(TimeZone::UTC) else {
+        return;
+    };
+
+    line(out, "BEGIN:VTIMEZONE");
+    line(out, &format!("TZID:{name}"));
+
+    // Walk a day at a time looking for offset changes, then narrow each one down to the second.
+    let mut prev = start.timestamp();
+    let (mut prev_offset, _, _) = tz.to_offset(prev);
+    let mut transitions = 0;
+    while prev < end.timestamp() {
+        let Ok(next) = prev.checked_add(24.hours()) else {
+            break;
+        };
+
+        let (offset, _, _) = tz.to_offset(next);
+        if offset != prev_offset {
+            let (mut lo, mut hi) = (prev.as_second(), next.as_second());
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                let mid_ts = Timestamp::from_second(mid).expect("between two valid timestamps");
+                if tz.to_offset(mid_ts).0 == prev_offset {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+
+            let at = Timestamp::from_second(hi).expect("between two valid timestamps");
+            observance(out, tz, at, prev_offset);
+            transitions += 1;
+            prev_offset = offset;
+        }
+
+        prev = next;
+    }
+
+    // Zones with a fixed offset still need one observance.
+    if transitions == 0 {
+        observance(out, tz, start.timestamp(), prev_offset);
+    }
+
+    line(out, "END:VTIMEZONE");
+}
+
+/// Writes a STANDARD or DAYLIGHT observance that starts at `at`.
+fn observance(out: &mut String, tz: &TimeZone, at: Timestamp, from: Offset) {
+    let (to, dst, abbrev) = tz.to_offset(at);
+    let kind = if dst.is_dst() { "DAYLIGHT" } else { "STANDARD" };
+
+    line(out, &format!("BEGIN:{kind}"));
+    // DTSTART is in the local time that was in effect before the transition.
+    line(
+        out,
+        &format!("DTSTART:{}", from.to_datetime(at).strftime("%Y%m%dT%H%M%S")),
+    );
+    line(out, &format!("TZOFFSETFROM:{}", utc_offset(from)));
+    line(out, &format!("TZOFFSETTO:{}", utc_offset(to)));
+    line(out, &format!("TZNAME:{}", escape(abbrev)));
+    line(out, &format!("END:{kind}"));
+}
+
+fn utc_offset(offset: Offset) -> String {
+    let secs = offset.seconds();
+    let sign = if secs < 0 { '-' } else { '+' };
+    let secs = secs.abs();
+
+    if secs % 60 == 0 {
+        format!("{sign}{:02}{:02}", secs / 3600, secs / 60 % 60)
+    } else {
+        format!(
+            "{sign}{:02}{:02}{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+/// Escapes a TEXT value.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out += "\\\\",
+            ';' => out += "\\;",
+            ',' => out += "\\,",
+            '\n' => out += "\\n",
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reverses [`escape`].
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') | Some('N') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Writes a content line, folding it so that no physical line is longer than 75 octets.
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Parses a DATE or DATE-TIME value, e.g. from DTSTART, given its parameters.
+pub fn parse_date(params: &str, value: &str) -> Option<Dateish> {
+    if params.contains("VALUE=DATE") && !params.contains("VALUE=DATE-TIME") {
+        let d = jiff::fmt::strtime::parse("%Y%m%d", value)
+            .ok()?
+            .to_date()
+            .ok()?;
+        return Some(Dateish::AllDay(d));
+    }
+
+    if let Some(utc) = value.strip_suffix('Z') {
+        let dt = jiff::fmt::strtime::parse("%Y%m%dT%H%M%S", utc)
+            .ok()?
+            .to_datetime()
+            .ok()?;
+        return Some(Dateish::Precise(dt.to_zoned(TimeZone::UTC).ok()?));
+    }
+
+    // Floating times and times in a named zone. Floating times are in whatever zone we're in.
+    let tz = params
+        .split(';')
+        .find_map(|p| p.strip_prefix("TZID="))
+        .and_then(|name| TimeZone::get(name.trim_matches('"')).ok())
+        .unwrap_or_else(TimeZone::system);
+    let dt = jiff::fmt::strtime::parse("%Y%m%dT%H%M%S", value)
+        .ok()?
+        .to_datetime()
+        .ok()?;
+
+    Some(Dateish::Precise(dt.to_zoned(tz).ok()?))
+}
+
+/// Splits an iCalendar object into unfolded `(name, params, value)` content lines.
+/// `params` includes its leading `;`, if there are any.
+pub fn content_lines(data: &str) -> Vec<(String, String, String)> {
+    let mut unfolded: Vec<String> = vec![];
+    for l in data.lines() {
+        let l = l.trim_end_matches('\r');
+        if let Some(cont) = l.strip_prefix([' ', '\t']) {
+            if let Some(last) = unfolded.last_mut() {
+                last.push_str(cont);
+                continue;
+            }
+        }
+        unfolded.push(l.to_string());
+    }
+
+    unfolded
+        .into_iter()
+        .filter_map(|l| {
+            let (head, value) = l.split_once(':')?;
+            let (name, params) = head.split_at(head.find(';').unwrap_or(head.len()));
+
+            Some((
+                name.to_ascii_uppercase(),
+                params.to_string(),
+                value.to_string(),
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,214 @@
+//! `cal-sync import`: the other direction. Events from Google calendars, like meetings other
+//! people invite us to, are written to an org file so they show up in the agenda.
+//!
+//! The file belongs to us: every import rewrites it from scratch, so edits to it are lost, and
+//! we refuse to touch a file we didn't write. Its headlines carry a `:GCAL_ID:`, which keeps
+//! them from being synced back.
+
+use std::{collections::HashSet, fs, io, path::Path};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use google_calendar::types::Event;
+use jiff::{civil::Date, tz::TimeZone, Zoned};
+
+use crate::{
+    atomic,
+    gcal::{GoogleBackend, Session},
+    org::{parse_duration, Dateish},
+};
+
+/// The first line of every file we write.
+const HEADER: &str = "# Imported by cal-sync; changes to this file are overwritten.";
+
+/// Parses a window like `-7d..+60d`, relative to `now`. Either end can be any org duration,
+/// with a sign in front for ones in the past.
+pub fn parse_window(s: &str, now: &Zoned) -> Option<(Zoned, Zoned)> {
+    let (start, end) = s.split_once("..")?;
+    let offset = |side: &str| {
+        let side = side.trim();
+        let (sign, duration) = match side.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, side.strip_prefix('+').unwrap_or(side)),
+        };
+        let secs = if duration == "0" {
+            0
+        } else {
+            parse_duration(duration)?
+        };
+        now.checked_add(jiff::Span::new().seconds(sign * secs)).ok()
+    };
+
+    let (start, end) = (offset(start)?, offset(end)?);
+    (start < end).then_some((start, end))
+}
+
+/// Imports the events in `window` from the calendars called `calendars` into the org file at
+/// `path`, writing times in `tz`. Returns how many events there were, and whether the file
+/// changed.
+pub async fn run(
+    session: Session,
+    calendars: &[String],
+    window: (Zoned, Zoned),
+    path: &Path,
+    tz: &TimeZone,
+) -> Result<(usize, bool)> {
+    let (start, end) = (window.0.timestamp(), window.1.timestamp());
+
+    let mut events = vec![];
+    // An event we were invited to shows up in every calendar it was added to.
+    let mut seen = HashSet::new();
+    for name in calendars {
+        let backend = GoogleBackend::new(session.clone(), name).await?;
+        for ev in backend.foreign_events(&start, &end).await? {
+            if seen.insert(ev.id.clone()) {
+                events.push((name.clone(), ev));
+            }
+        }
+    }
+    // Calendars list their own events in order, but not each other's.
+    events.sort_by_cached_key(|(_, ev)| {
+        let start = ev.start.as_ref().and_then(Dateish::from_gcal);
+        (start.map(|s| s.canonical()), ev.id.clone())
+    });
+
+    let count = events.len();
+    let changed = write(path, &render(&events, tz))?;
+
+    Ok((count, changed))
+}
+
+/// The org file for `events`, each with the calendar it came from.
+pub fn render(events: &[(String, Event)], tz: &TimeZone) -> String {
+    let mut out = format!("{HEADER}\n");
+    for (calendar, ev) in events {
+        let Some(when) = timestamp(ev, tz) else {
+            continue;
+        };
+        let mut title = ev.summary.split_whitespace().collect::<Vec<_>>().join(" ");
+        if title.is_empty() {
+            title = "(No title)".to_string();
+        }
+
+        out.push('\n');
+        out.push_str(&format!("* {title}\n"));
+        out.push_str(":PROPERTIES:\n");
+        out.push_str(&format!(":ID: gcal-{}\n", ev.id));
+        out.push_str(&format!(":GCAL_ID: {}\n", ev.id));
+        out.push_str(&format!(":GCAL_CALENDAR: {calendar}\n"));
+        let location = ev.location.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !location.is_empty() {
+            out.push_str(&format!(":LOCATION: {location}\n"));
+        }
+        out.push_str(":END:\n");
+        out.push_str(&when);
+        out.push('\n');
+
+        let attendees: Vec<_> = ev
+            .attendees
+            .iter()
+            .map(|a| match a.display_name.as_str() {
+                "" => a.email.clone(),
+                name => format!("{name} <{}>", a.email),
+            })
+            .collect();
+        if !attendees.is_empty() {
+            out.push_str(&format!("Attendees: {}\n", attendees.join(", ")));
+        }
+
+        let description = ev.description.trim();
+        if !description.is_empty() {
+            out.push('\n');
+            for line in description.lines() {
+                // Anything that starts with a star would be read as a headline.
+                if line.starts_with('*') {
+                    out.push(' ');
+                }
+                out.push_str(line.trim_end());
+                out.push('\n');
+            }
+        }
+    }
+
+    out
+}
+
+/// When `ev` happens, as an org timestamp in `tz`.
+fn timestamp(ev: &Event, tz: &TimeZone) -> Option<String> {
+    let start = Dateish::from_gcal(ev.start.as_ref()?)?;
+    let end = ev.end.as_ref().and_then(Dateish::from_gcal);
+
+    let day = |d: Date| d.strftime("%Y-%m-%d %a").to_string();
+    let ts = match (start, end) {
+        (Dateish::AllDay(s), Some(Dateish::AllDay(e))) => {
+            // Google's all-day events end the day after their last one.
+            let last = e.yesterday().unwrap_or(e);
+            if last <= s {
+                format!("<{}>", day(s))
+            } else {
+                format!("<{}>--<{}>", day(s), day(last))
+            }
+        }
+        (Dateish::AllDay(s), _) => format!("<{}>", day(s)),
+        (Dateish::Precise(s), end) => {
+            let s = s.with_time_zone(tz.clone());
+            match end {
+                Some(Dateish::Precise(e)) => {
+                    let e = e.with_time_zone(tz.clone());
+                    if e.date() == s.date() {
+                        format!(
+                            "<{} {}-{}>",
+                            day(s.date()),
+                            s.strftime("%H:%M"),
+                            e.strftime("%H:%M")
+                        )
+                    } else {
+                        format!(
+                            "<{} {}>--<{} {}>",
+                            day(s.date()),
+                            s.strftime("%H:%M"),
+                            day(e.date()),
+                            e.strftime("%H:%M")
+                        )
+                    }
+                }
+                _ => format!("<{} {}>", day(s.date()), s.strftime("%H:%M")),
+            }
+        }
+    };
+
+    Some(ts)
+}
+
+/// Replaces the file at `path` with `contents`, unless it already has them. Returns whether it
+/// changed.
+fn write(path: &Path, contents: &str) -> Result<bool> {
+    match fs::read_to_string(path) {
+        Ok(old) if old == contents => return Ok(false),
+        Ok(old) if !old.is_empty() && old.lines().next() != Some(HEADER) => {
+            return Err(eyre!(
+                "{} wasn't written by cal-sync import; refusing to overwrite it",
+                path.to_string_lossy()
+            ))
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        // Including files that aren't UTF-8, which we certainly didn't write.
+        Err(e) => {
+            return Err(e).wrap_err_with(|| {
+                format!(
+                    "Couldn't read {}; refusing to overwrite it",
+                    path.to_string_lossy()
+                )
+            })
+        }
+    }
+
+    // Write next to it and move it over, so the agenda never sees half a file.
+    atomic::write(path, contents.as_bytes(), 0o644)
+        .wrap_err_with(|| format!("Couldn't write {}", path.to_string_lossy()))?;
+
+    Ok(true)
+}
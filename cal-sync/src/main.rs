@@ -1,84 +1,293 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+};
 
 use argh::FromArgs;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, OptionExt, Result, WrapErr};
 use tracing::{debug, info};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+mod atomic;
+mod backend;
+mod batch;
+mod caldav;
+mod config;
+mod filter;
 mod gcal;
+mod ics;
+mod import;
+mod oauth;
 mod org;
+mod plan;
+mod report;
+mod retry;
+mod state;
+mod sync;
+mod token;
+mod watch;
+mod writeback;
+
+#[cfg(test)]
+mod tests;
+
+use backend::CalendarBackend;
+use config::{Backend, CalendarConfig, Config};
+use report::{AuthFailed, OrgFailed, RunReport};
+use retry::Throttled;
+use state::SyncState;
+
+/// Env var the CalDAV password is read from, so it doesn't end up in shell history.
+const CALDAV_PASSWORD_VAR: &str = "CAL_SYNC_CALDAV_PASSWORD";
+/// Env var holding the passphrase Google tokens are encrypted with, if they are.
+const TOKEN_PASSPHRASE_VAR: &str = "CAL_SYNC_TOKEN_PASSPHRASE";
 
 #[derive(FromArgs)]
 /// Sync org and gcal.
 struct Args {
+    #[argh(subcommand)]
+    command: Option<Command>,
+
     #[argh(positional)]
-    path: PathBuf,
+    /// org directory to sync
+    path: Option<PathBuf>,
+
+    #[argh(option)]
+    /// config file (default: ~/.config/org-tools/cal-sync.toml)
+    config: Option<PathBuf>,
+
+    #[argh(option)]
+    /// name (summary) of the default calendar, or its collection URL for caldav
+    calendar: Option<String>,
+
+    #[argh(option)]
+    /// calendar service to sync to: google (default) or caldav
+    backend: Option<Backend>,
 
     #[argh(option)]
-    /// name (summary) of target calendar
-    calendar: String,
+    /// how to sign in to google without a token: loopback (default), manual or service-account
+    auth: Option<config::Auth>,
 
     #[argh(option)]
-    /// credential path
-    creds: PathBuf,
+    /// IANA time zone org timestamps are in, unless a headline sets :TIMEZONE: (default: the
+    /// system's)
+    tz: Option<String>,
 
     #[argh(option)]
-    /// token path
-    token: PathBuf,
+    /// what to do with times DST skips or repeats: compatible (default), earlier, later or reject
+    dst: Option<config::Dst>,
+
+    #[argh(switch)]
+    /// don't sync anything if an org file can't be read or has invalid timestamps
+    strict: bool,
+
+    #[argh(option)]
+    /// credential path, or service account key (google)
+    creds: Option<PathBuf>,
+
+    #[argh(option)]
+    /// token path (google); encrypted if CAL_SYNC_TOKEN_PASSPHRASE is set
+    token: Option<PathBuf>,
+
+    #[argh(option)]
+    /// username (caldav); the password is read from CAL_SYNC_CALDAV_PASSWORD
+    caldav_user: Option<String>,
+
+    #[argh(option)]
+    /// write events to this iCalendar file instead of syncing to a calendar service
+    ics: Option<PathBuf>,
+
+    #[argh(option, default = "org::AllDay::All")]
+    /// which all-day timestamps to sync: all (default), deadlines or none
+    all_day: org::AllDay,
+
+    #[argh(option)]
+    /// only sync all-day timestamps on headlines with this tag (repeatable)
+    all_day_tag: Vec<String>,
+
+    #[argh(option)]
+    /// file to remember what was synced in between runs
+    state: Option<PathBuf>,
+
+    #[argh(switch)]
+    /// move timestamps in org files when their events are moved in the calendar (needs --state)
+    two_way: bool,
 
     #[argh(switch)]
     /// don't actually modify gcal
     dry: bool,
 
     #[argh(switch)]
-    /// print error to stdout
+    /// keep running, and re-sync whenever org files change
+    watch: bool,
+
+    #[argh(switch)]
+    /// show what would be created, updated and deleted without changing anything
+    plan: bool,
+
+    #[argh(switch)]
+    /// print --plan as JSON
+    json: bool,
+
+    #[argh(option, default = "Format::Human")]
+    /// what to print: human (default), or json for a report of the whole run
+    format: Format,
+
+    #[argh(switch)]
+    /// print the error to stderr
     show_err: bool,
 }
 
+/// How the result of a run is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected human or json, got {s}")),
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Import(ImportArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Write events from google calendars to an org file.
+struct ImportArgs {
+    #[argh(positional)]
+    /// org file to write; only ever one written by import
+    file: PathBuf,
+
+    #[argh(option)]
+    /// name of a calendar to import from (repeatable)
+    from: Vec<String>,
+
+    #[argh(option, default = "String::from(\"-7d..+60d\")")]
+    /// which events to import, relative to now (default: -7d..+60d)
+    window: String,
+}
+
+/// Exits with one of [`report::Status`]'s codes, so monitoring can tell failures apart.
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
+    // Logs go to stderr, so they don't get mixed up with the report.
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(std::io::stderr))
         .with(EnvFilter::from_default_env())
         .init();
 
     let args: Args = argh::from_env();
+    let config = load_config(&args)?;
+
+    if let Some(Command::Import(import)) = &args.command {
+        run_import(&config, import).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let path = args
+        .path
+        .clone()
+        .ok_or_eyre("Expected the org directory to sync")?;
+    if args.watch && args.format == Format::Json {
+        return Err(eyre!("--watch can't be used with --format json"));
+    }
+    let human = args.format == Format::Human;
 
     let before_items = jiff::Timestamp::now();
-    let items = org::get_valid_items(args.path);
+    let options = org::Options {
+        todo_keywords: config.todo_keywords.clone(),
+        done_keywords: config.done_keywords.clone(),
+        default_duration: config.default_duration(),
+        all_day: args.all_day,
+        all_day_tags: args.all_day_tag.clone(),
+        clocks: config.clock.is_some(),
+        tz: config.time_zone(),
+        dst: config.dst.disambiguation(),
+        ignore: config.ignore_timestamps_in.clone(),
+    };
+    let parsed = org::get_valid_items(path.clone(), &options);
     let after_items = jiff::Timestamp::now();
 
-    info!("{} items", items.len());
-    for item in &items {
+    info!("{} items", parsed.items.len());
+    for item in &parsed.items {
         debug!("{} {:?}", item.name, item.timestamps);
     }
+    if human {
+        for w in &parsed.warnings {
+            println!("! {w}");
+        }
+        if !parsed.skipped.is_empty() {
+            println!("✗ skipped {} org files:", parsed.skipped.len());
+            for s in &parsed.skipped {
+                println!("  {s}");
+            }
+        }
+    }
+    let mut report = RunReport::new(&parsed);
 
     if !args.dry {
-        let client = match gcal::get_client(args.creds, args.token).await {
-            Ok(o) => o,
-            Err(e) => {
-                println!("✗ err");
-                if args.show_err {
-                    return Err(e);
+        let before_sync = jiff::Timestamp::now();
+        let res = if config.strict && parsed.has_problems() {
+            Err(eyre!(
+                "{} files couldn't be read, and {} had warnings",
+                report.skipped.len(),
+                report.warnings.len()
+            )
+            .wrap_err(OrgFailed))
+        } else {
+            run_sync(&args, &path, &config, &options, parsed.items).await
+        };
+        let after_sync = jiff::Timestamp::now();
+        report.time(before_items, after_items, Some(after_sync));
+
+        let res = match res {
+            Ok(Some(outcome)) => {
+                report.add_outcome(&outcome);
+                if human {
+                    outcome.report()
                 } else {
-                    return Ok(());
+                    Ok(())
                 }
             }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                report.fail(&e);
+                Err(e)
+            }
         };
+        let code = ExitCode::from(report.exit_code);
 
-        let before_sync = jiff::Timestamp::now();
-        match gcal::sync(client, items, &args.calendar).await {
-            Ok(()) => {}
-            Err(e) => {
-                println!("✗ err");
-                if args.show_err {
-                    return Err(e);
-                } else {
-                    return Ok(());
-                }
+        if !human {
+            // --plan already printed its own JSON, unless it couldn't get that far.
+            if !args.plan || report.error.is_some() {
+                println!("{}", serde_json::to_string_pretty(&report)?);
             }
+            return Ok(code);
+        }
+        if let Err(e) = res {
+            println!("✗ err");
+            if args.show_err {
+                eprintln!("Error: {e:?}");
+            }
+            return Ok(code);
+        }
+        if args.json {
+            return Ok(code);
         }
-        let after_sync = jiff::Timestamp::now();
 
         println!("---");
         println!("parsed org files in {:#}", after_items - before_items);
@@ -88,15 +297,317 @@ async fn main() -> Result<()> {
             "it is {}",
             jiff::fmt::strtime::format("%b %-d %-I:%M%P", &jiff::Zoned::now()).unwrap()
         );
+
+        Ok(code)
     } else {
-        println!("✓ {}", items.len());
+        report.time(before_items, after_items, None);
+        if !human {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(ExitCode::from(report.exit_code));
+        }
+
+        println!("✓ {}", report.items);
         println!("---");
         println!("parsed org files in {:#}", after_items - before_items);
         println!(
             "it is {}",
             jiff::fmt::strtime::format("%b %-d %-I:%M%P", &jiff::Zoned::now()).unwrap()
         );
+
+        Ok(ExitCode::from(report.exit_code))
+    }
+}
+
+/// Reads the config file and applies the command line on top of it.
+fn load_config(args: &Args) -> Result<Config> {
+    let mut config = match (&args.config, Config::default_path()) {
+        (Some(path), _) => Config::load(path, true)?,
+        (None, Some(path)) => Config::load(&path, false)?,
+        (None, None) => Config::default(),
+    };
+
+    if let Some(backend) = args.backend {
+        config.backend = backend;
+    }
+    if let Some(auth) = args.auth {
+        config.auth = auth;
+    }
+    if let Some(tz) = &args.tz {
+        config.tz = Some(tz.clone());
+    }
+    if let Some(dst) = args.dst {
+        config.dst = dst;
+    }
+    if args.strict {
+        config.strict = true;
+    }
+    if let Some(creds) = &args.creds {
+        config.creds = Some(creds.clone());
+    }
+    if let Some(token) = &args.token {
+        config.token = Some(token.clone());
+    }
+    if let Some(state) = &args.state {
+        config.state = Some(state.clone());
+    }
+    if let Some(user) = &args.caldav_user {
+        config.caldav_user = Some(user.clone());
+    }
+    // Without any calendars configured, everything goes to the one given on the command line.
+    if args.calendar.is_some() || config.calendars.is_empty() {
+        let cal = config
+            .calendars
+            .entry(config.default_calendar.clone())
+            .or_insert_with(|| CalendarConfig {
+                name: String::new(),
+                color: None,
+                title: None,
+                reminders: None,
+            });
+        if let Some(name) = &args.calendar {
+            cal.name = name.clone();
+        }
+    }
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+/// Syncs `items` the way `args` say to. Returns what happened to each calendar, unless we were
+/// writing an iCalendar file or watching.
+async fn run_sync(
+    args: &Args,
+    path: &Path,
+    config: &Config,
+    options: &org::Options,
+    items: Vec<org::AgendaItem>,
+) -> Result<Option<sync::Outcome>> {
+    let root = std::path::absolute(path)?;
+
+    if args.json && !args.plan {
+        return Err(eyre!("--json needs --plan"));
+    }
+    if args.plan && args.ics.is_some() {
+        return Err(eyre!("--plan can't be used with --ics"));
+    }
+    if args.watch && (args.plan || args.ics.is_some()) {
+        return Err(eyre!("--watch can't be used with --plan or --ics"));
+    }
+    if let Some(path) = &args.ics {
+        let events: Vec<_> = sync::sync_events(items, config, &root)
+            .into_values()
+            .flatten()
+            .collect();
+        let count = ics::write(path, &events)?;
+        if args.format == Format::Human {
+            println!("+{count}");
+        }
+        return Ok(None);
+    }
+
+    if let Some((id, _)) = config.calendars.iter().find(|(_, c)| c.name.is_empty()) {
+        return Err(eyre!(
+            "calendar {id} has no name; pass --calendar unless writing --ics"
+        ));
+    }
+    if args.two_way && config.state.is_none() {
+        return Err(eyre!("--two-way needs --state"));
+    }
+
+    match config.backend {
+        Backend::Google => {
+            let session = google_session(config).await.wrap_err(AuthFailed)?;
+            let backends = google_backends(config, &session).await;
+            let reconnect = GoogleReconnect { config, session };
+
+            sync_all(args, config, options, &root, backends, reconnect, items).await
+        }
+        Backend::CalDav => {
+            let password = std::env::var(CALDAV_PASSWORD_VAR).ok();
+            if config.caldav_user.is_some() && password.is_none() {
+                return Err(eyre!(
+                    "{CALDAV_PASSWORD_VAR} must be set with --caldav-user"
+                ));
+            }
+
+            let backends: BTreeMap<_, _> = config
+                .calendars
+                .iter()
+                .map(|(id, cal)| {
+                    let backend = caldav::CalDavBackend::new(
+                        &cal.name,
+                        config.caldav_user.clone(),
+                        password.clone(),
+                    )
+                    .map(|b| Throttled::new(b, config.concurrency, config.retry()));
+                    (id.clone(), backend)
+                })
+                .collect();
+
+            sync_all(
+                args,
+                config,
+                options,
+                &root,
+                backends,
+                watch::StaysConnected,
+                items,
+            )
+            .await
+        }
+    }
+}
+
+/// Signs in to Google the way `config` says to.
+async fn google_session(config: &Config) -> Result<gcal::Session> {
+    let creds = config
+        .creds
+        .clone()
+        .ok_or_eyre("--creds is required for google")?;
+
+    gcal::get_client(
+        config.auth,
+        creds,
+        token_store(config),
+        config.port,
+        &config.google_endpoints(),
+    )
+    .await
+}
+
+/// Where Google tokens are kept, if anywhere.
+fn token_store(config: &Config) -> Option<token::TokenStore> {
+    let passphrase = std::env::var(TOKEN_PASSPHRASE_VAR).ok();
+    config
+        .token
+        .clone()
+        .map(|path| token::TokenStore::new(path, passphrase))
+}
+
+/// Finds each of `config`'s calendars.
+async fn google_backends(
+    config: &Config,
+    session: &gcal::Session,
+) -> BTreeMap<String, Result<Throttled<gcal::GoogleBackend>>> {
+    let retry = config.retry();
+    let mut backends = BTreeMap::new();
+    for (id, cal) in &config.calendars {
+        let backend = retry
+            .run(&format!("find {}", cal.name), || {
+                gcal::GoogleBackend::new(session.clone(), &cal.name)
+            })
+            .await
+            .map(|b| Throttled::new(b, config.concurrency, retry.clone()));
+        backends.insert(id.clone(), backend);
+    }
+
+    backends
+}
+
+/// Refreshes the Google access token before it expires, with `--watch`.
+struct GoogleReconnect<'a> {
+    config: &'a Config,
+    session: gcal::Session,
+}
+
+impl watch::Reconnect<Throttled<gcal::GoogleBackend>> for GoogleReconnect<'_> {
+    async fn reconnect(
+        &mut self,
+    ) -> Result<Option<BTreeMap<String, Result<Throttled<gcal::GoogleBackend>>>>> {
+        if !self.session.expires_soon() {
+            return Ok(None);
+        }
+
+        let creds = self
+            .config
+            .creds
+            .as_deref()
+            .ok_or_eyre("--creds is required for google")?;
+        self.session = gcal::refresh(
+            self.config.auth,
+            creds,
+            token_store(self.config).as_ref(),
+            &self.config.google_endpoints(),
+        )
+        .await?;
+
+        Ok(Some(google_backends(self.config, &self.session).await))
+    }
+}
+
+/// Writes events from Google calendars to an org file.
+async fn run_import(config: &Config, args: &ImportArgs) -> Result<()> {
+    if config.backend != Backend::Google {
+        return Err(eyre!("import only works with google calendars"));
+    }
+    if args.from.is_empty() {
+        return Err(eyre!("import needs at least one --from calendar"));
+    }
+    let tz = config.time_zone();
+    let now = jiff::Zoned::now().with_time_zone(tz.clone());
+    let window = import::parse_window(&args.window, &now).ok_or_else(|| {
+        eyre!(
+            "--window: invalid window {:?}, expected e.g. -7d..+60d",
+            args.window
+        )
+    })?;
+
+    let session = google_session(config).await?;
+    let (count, changed) = import::run(session, &args.from, window, &args.file, &tz).await?;
+    if changed {
+        println!(
+            "✓ imported {count} events to {}",
+            args.file.to_string_lossy()
+        );
+    } else {
+        println!("✓ {} is up to date", args.file.to_string_lossy());
     }
 
     Ok(())
 }
+
+/// Syncs every calendar once, or keeps them in sync with `--watch`.
+async fn sync_all<B: CalendarBackend>(
+    args: &Args,
+    config: &Config,
+    options: &org::Options,
+    root: &Path,
+    backends: BTreeMap<String, Result<B>>,
+    reconnect: impl watch::Reconnect<B>,
+    items: Vec<org::AgendaItem>,
+) -> Result<Option<sync::Outcome>> {
+    if args.watch {
+        watch::run(
+            config,
+            options,
+            root,
+            backends,
+            reconnect,
+            items,
+            args.two_way,
+        )
+        .await?;
+        return Ok(None);
+    }
+
+    let calendars = sync::sync_events(items, config, root);
+    let mut state = config.state.as_deref().map(SyncState::load).transpose()?;
+
+    let outcome = sync::sync_calendars(
+        &backends,
+        calendars,
+        state.as_mut(),
+        args.two_way,
+        args.plan,
+    )
+    .await;
+
+    if args.plan {
+        plan::print(&outcome.plans, args.json || args.format == Format::Json)?;
+    } else if let (Some(path), Some(state)) = (&config.state, state) {
+        state.save(path)?;
+    }
+
+    Ok(Some(outcome))
+}
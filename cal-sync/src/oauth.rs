@@ -0,0 +1,337 @@
+//! Getting access tokens from Google: refreshing the ones we have, asking a user for consent
+//! when we don't have any, or signing in as a service account.
+//!
+//! Consent goes through Google's flow for installed apps. Either we listen on a loopback port
+//! for the browser to be redirected back to us, or, on machines without a browser, the user
+//! opens the link somewhere else and pastes back the address they end up at. Both check the
+//! `state` we sent and use PKCE, so a code that isn't meant for us is no good to us.
+
+use std::{collections::HashMap, fs, future::IntoFuture, path::Path, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use color_eyre::{
+    eyre::{eyre, OptionExt, WrapErr},
+    Result,
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::gcal::Endpoints;
+
+pub const SCOPES: [&str; 2] = [
+    "https://www.googleapis.com/auth/calendar.readonly",
+    "https://www.googleapis.com/auth/calendar.events",
+];
+
+/// How long we wait for the browser to come back to the loopback listener.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Service account tokens are good for an hour at most.
+const ASSERTION_LIFETIME: i64 = 3600;
+
+/// The OAuth client we are, from the credentials file Google hands out.
+#[derive(Debug, Deserialize)]
+pub struct ClientCreds {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CredsFile {
+    installed: ClientCreds,
+}
+
+impl ClientCreds {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read credentials {}", path.to_string_lossy()))?;
+        let file: CredsFile = serde_json::from_str(&data).wrap_err_with(|| {
+            format!(
+                "Invalid credentials {}, expected a desktop app's client secret",
+                path.to_string_lossy()
+            )
+        })?;
+
+        Ok(file.installed)
+    }
+}
+
+/// A request for a user's consent, and what we need to check and redeem the answer.
+pub struct Consent {
+    pub redirect_uri: String,
+    state: String,
+    verifier: String,
+}
+
+impl Consent {
+    pub fn new(redirect_uri: String) -> Result<Self> {
+        Ok(Self {
+            redirect_uri,
+            state: random_token(16)?,
+            verifier: random_token(32)?,
+        })
+    }
+
+    /// The page the user has to visit.
+    pub fn url(&self, endpoints: &Endpoints, creds: &ClientCreds) -> Result<String> {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(&self.verifier));
+        let url = reqwest::Url::parse_with_params(
+            &endpoints.auth,
+            [
+                ("client_id", creds.client_id.as_str()),
+                ("redirect_uri", &self.redirect_uri),
+                ("response_type", "code"),
+                ("scope", &SCOPES.join(" ")),
+                ("state", &self.state),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+                // Without these we'd only get a refresh token the very first time.
+                ("access_type", "offline"),
+                ("prompt", "consent"),
+            ],
+        )?;
+
+        Ok(url.into())
+    }
+
+    /// Checks the query of a redirect back to [`Consent::redirect_uri`], returning its code.
+    pub fn code(&self, query: &HashMap<String, String>) -> Result<String> {
+        if let Some(error) = query.get("error") {
+            return Err(eyre!("Consent wasn't given: {error}"));
+        }
+        if query.get("state") != Some(&self.state) {
+            return Err(eyre!(
+                "Redirect isn't for the consent we asked for (state mismatch)"
+            ));
+        }
+
+        query
+            .get("code")
+            .cloned()
+            .ok_or_eyre("Redirect has no authorization code")
+    }
+
+    /// Trades the code from a redirect for tokens.
+    pub async fn redeem(
+        &self,
+        http: &reqwest::Client,
+        endpoints: &Endpoints,
+        creds: &ClientCreds,
+        code: &str,
+    ) -> Result<serde_json::Value> {
+        post_form(
+            http,
+            &endpoints.token,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("code_verifier", &self.verifier),
+                ("redirect_uri", &self.redirect_uri),
+                ("client_id", &creds.client_id),
+                ("client_secret", &creds.client_secret),
+            ],
+        )
+        .await
+    }
+}
+
+/// Gets consent through a browser that's redirected back to a listener on `port`, or any free
+/// port if there isn't one. `show` is given the page the user has to visit.
+pub async fn loopback(
+    http: &reqwest::Client,
+    endpoints: &Endpoints,
+    creds: &ClientCreds,
+    port: Option<u16>,
+    show: impl FnOnce(&str),
+) -> Result<serde_json::Value> {
+    // Only this machine should be able to hand us a code.
+    let listener = TcpListener::bind(("127.0.0.1", port.unwrap_or(0)))
+        .await
+        .wrap_err("Couldn't start the OAuth redirect listener")?;
+    let port = listener.local_addr()?.port();
+
+    let consent = Arc::new(Consent::new(format!("http://127.0.0.1:{port}"))?);
+    show(&consent.url(endpoints, creds)?);
+
+    let code = listen(listener, consent.clone()).await?;
+    consent.redeem(http, endpoints, creds, &code).await
+}
+
+/// Serves redirects until one of them is for `consent`, returning its code.
+async fn listen(listener: TcpListener, consent: Arc<Consent>) -> Result<String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let app = Router::new()
+        .route(
+            "/",
+            get(
+                |State((consent, tx)): State<(Arc<Consent>, mpsc::Sender<String>)>,
+                 Query(query): Query<HashMap<String, String>>| async move {
+                    match consent.code(&query) {
+                        Ok(code) => {
+                            let _ = tx.try_send(code);
+                            (
+                                StatusCode::OK,
+                                "Signed in. You can close this window.".to_string(),
+                            )
+                        }
+                        Err(e) => (StatusCode::BAD_REQUEST, format!("{e}")),
+                    }
+                },
+            ),
+        )
+        .with_state((consent, tx));
+
+    // Dropping the server once we have a code stops it accepting connections, but lets the one
+    // we're answering finish.
+    tokio::select! {
+        code = rx.recv() => code.ok_or_eyre("OAuth redirect listener stopped"),
+        res = axum::serve(listener, app).into_future() => {
+            res?;
+            Err(eyre!("OAuth redirect listener stopped"))
+        }
+        _ = tokio::time::sleep(TIMEOUT) => Err(eyre!(
+            "Timed out waiting for consent; use auth = \"manual\" on machines without a browser"
+        )),
+    }
+}
+
+/// Gets consent without a listener: `ask` is given the page the user has to visit, and returns
+/// the address their browser ended up at afterwards.
+pub async fn manual(
+    http: &reqwest::Client,
+    endpoints: &Endpoints,
+    creds: &ClientCreds,
+    ask: impl FnOnce(&str) -> Result<String>,
+) -> Result<serde_json::Value> {
+    // Nothing listens here, so the browser shows an error page, but the code is in its address
+    // bar.
+    let consent = Consent::new("http://127.0.0.1".to_string())?;
+    let answer = ask(&consent.url(endpoints, creds)?)?;
+
+    let url = reqwest::Url::parse(answer.trim())
+        .wrap_err("Expected the whole address the browser ended up at")?;
+    let query = url.query_pairs().into_owned().collect();
+    let code = consent.code(&query)?;
+
+    consent.redeem(http, endpoints, creds, &code).await
+}
+
+/// Trades a refresh token for a new access token.
+pub async fn refresh(
+    http: &reqwest::Client,
+    endpoints: &Endpoints,
+    creds: &ClientCreds,
+    refresh_token: &str,
+) -> Result<serde_json::Value> {
+    post_form(
+        http,
+        &endpoints.token,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &creds.client_id),
+            ("client_secret", &creds.client_secret),
+        ],
+    )
+    .await
+}
+
+/// The parts of a service account's key file we need.
+#[derive(Debug, Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: String,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+/// Signs in as the service account whose key is at `key_path`, returning an access token and
+/// when it expires, in seconds since the epoch. The account only sees calendars that have been
+/// shared with it.
+pub async fn service_account(
+    http: &reqwest::Client,
+    endpoints: &Endpoints,
+    key_path: &Path,
+) -> Result<(String, Option<i64>)> {
+    let data = fs::read_to_string(key_path).wrap_err_with(|| {
+        format!(
+            "Couldn't read service account key {}",
+            key_path.to_string_lossy()
+        )
+    })?;
+    let key: ServiceAccountKey = serde_json::from_str(&data)
+        .wrap_err_with(|| format!("Invalid service account key {}", key_path.to_string_lossy()))?;
+
+    let now = jiff::Timestamp::now().as_second();
+    let claims = Claims {
+        iss: &key.client_email,
+        scope: SCOPES.join(" "),
+        aud: &endpoints.token,
+        iat: now,
+        exp: now + ASSERTION_LIFETIME,
+    };
+    let assertion = jsonwebtoken::encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+            .wrap_err("Invalid private key in service account key")?,
+    )?;
+
+    let tok = post_form(
+        http,
+        &endpoints.token,
+        &[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &assertion),
+        ],
+    )
+    .await?;
+
+    let access_token = tok["access_token"]
+        .as_str()
+        .ok_or_eyre("No access token in service account response")?;
+    let expires_at = tok["expires_in"]
+        .as_i64()
+        .map(|secs| jiff::Timestamp::now().as_second() + secs);
+
+    Ok((access_token.to_string(), expires_at))
+}
+
+/// Posts `form` to a token endpoint, keeping the body of any error, which says what went wrong.
+async fn post_form(
+    http: &reqwest::Client,
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<serde_json::Value> {
+    let resp = http.post(url).form(form).send().await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if !status.is_success() {
+        return Err(eyre!("{url}: {status}: {body}"));
+    }
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// `bytes` random bytes, encoded so they can go in a URL.
+fn random_token(bytes: usize) -> Result<String> {
+    let mut buf = vec![0; bytes];
+    getrandom::getrandom(&mut buf).map_err(|e| eyre!("Couldn't get random bytes: {e}"))?;
+
+    Ok(URL_SAFE_NO_PAD.encode(buf))
+}
@@ -1,150 +1,599 @@
-use std::{ffi::OsStr, fs, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
+use chrono::Datelike;
 use google_calendar::types::EventDateTime;
 use jiff::{
-    civil::{date, Date},
-    tz::TimeZone,
-    ToSpan, Zoned,
+    civil::{Date, Time},
+    tz::{Disambiguation, TimeZone},
+    Span, ToSpan, Zoned,
 };
 use orgize::{
+    ast::PropertyDrawer,
     export::{Container, Event, TraversalContext, Traverser},
-    ParseConfig,
+    ParseConfig, TextRange,
 };
 use rayon::prelude::*;
+use serde::Deserialize;
 
-const DONE_KEYWORDS: [&str; 2] = ["DONE", "CNCL"];
+/// Which all-day timestamps to sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllDay {
+    All,
+    Deadlines,
+    None,
+}
 
-pub fn get_valid_items(path: PathBuf) -> Vec<AgendaItem> {
-    let parse_config = ParseConfig {
-        todo_keywords: (
-            vec!["TODO".to_string(), "DOIN".to_string()],
-            DONE_KEYWORDS.into_iter().map(|s| s.to_string()).collect(),
-        ),
-        ..Default::default()
-    };
-    let now = Zoned::now();
+impl FromStr for AllDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(AllDay::All),
+            "deadlines" => Ok(AllDay::Deadlines),
+            "none" => Ok(AllDay::None),
+            _ => Err(format!(
+                "unknown all-day mode {s}, expected all, deadlines or none"
+            )),
+        }
+    }
+}
+
+/// Parts of an entry whose timestamps can be ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Context {
+    /// Property drawers, `:LOGBOOK:` and other drawers.
+    Drawers,
+    /// `#+BEGIN_SRC`, `#+BEGIN_EXAMPLE`, quotes and other blocks.
+    Blocks,
+    Tables,
+}
+
+impl Context {
+    /// What kind of context `container` is, if it's one at all.
+    fn of(container: &Container) -> Option<Self> {
+        match container {
+            Container::Drawer(_) | Container::PropertyDrawer(_) => Some(Context::Drawers),
+            Container::SourceBlock(_)
+            | Container::ExampleBlock(_)
+            | Container::ExportBlock(_)
+            | Container::CommentBlock(_)
+            | Container::QuoteBlock(_)
+            | Container::CenterBlock(_)
+            | Container::VerseBlock(_)
+            | Container::SpecialBlock(_)
+            | Container::DynBlock(_) => Some(Context::Blocks),
+            Container::OrgTable(_) | Container::TableEl(_) => Some(Context::Tables),
+            _ => None,
+        }
+    }
+}
+
+/// Controls which timestamps end up as items.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub todo_keywords: Vec<String>,
+    /// Headlines with one of these are never synced.
+    pub done_keywords: Vec<String>,
+    /// How long timestamps without an end last, in seconds.
+    pub default_duration: i64,
+    pub all_day: AllDay,
+    /// If non-empty, only all-day timestamps on headlines with (or inheriting) one of these tags
+    /// are synced.
+    pub all_day_tags: Vec<String>,
+    /// Whether to collect `CLOCK:` entries into [`AgendaItem::clocks`].
+    pub clocks: bool,
+    /// The zone timestamps are in, unless a headline says otherwise.
+    pub tz: TimeZone,
+    /// How to read times that DST transitions skip over or repeat.
+    pub dst: Disambiguation,
+    /// Timestamps in these parts of an entry aren't synced.
+    pub ignore: Vec<Context>,
+}
+
+impl Options {
+    /// The current time, in [`Options::tz`].
+    pub fn now(&self) -> Zoned {
+        Zoned::now().with_time_zone(self.tz.clone())
+    }
+
+    fn keep_all_day(&self, kind: TimestampKind, tags: &[String]) -> bool {
+        let kind_ok = match self.all_day {
+            AllDay::All => true,
+            AllDay::Deadlines => kind == TimestampKind::Deadline,
+            AllDay::None => false,
+        };
+
+        kind_ok
+            && (self.all_day_tags.is_empty() || tags.iter().any(|t| self.all_day_tags.contains(t)))
+    }
+}
+
+/// The items in some org files, and what was wrong with them.
+#[derive(Debug, Default)]
+pub struct Parsed {
+    pub items: Vec<AgendaItem>,
+    pub warnings: Vec<Warning>,
+    /// Files (or directories) that couldn't be read at all.
+    pub skipped: Vec<Skipped>,
+}
+
+impl Parsed {
+    fn merge(mut self, other: Parsed) -> Self {
+        self.items.extend(other.items);
+        self.warnings.extend(other.warnings);
+        self.skipped.extend(other.skipped);
+        self
+    }
+
+    fn skipped(path: &Path, reason: String) -> Self {
+        Self {
+            skipped: vec![Skipped {
+                path: path.to_owned(),
+                reason,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Whether any file had problems.
+    pub fn has_problems(&self) -> bool {
+        !self.warnings.is_empty() || !self.skipped.is_empty()
+    }
+}
+
+/// Something wrong with an org file that we worked around, usually by skipping a timestamp.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub path: PathBuf,
+    /// Where in the file, unless it's about the whole file.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}:{line}: {}",
+                self.path.to_string_lossy(),
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.to_string_lossy(), self.message),
+        }
+    }
+}
+
+/// An org file we couldn't read, and why.
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.to_string_lossy(), self.reason)
+    }
+}
+
+pub fn get_valid_items(path: PathBuf, options: &Options) -> Parsed {
+    let now = options.now();
 
-    walkdir::WalkDir::new(path)
+    let mut parsed = walkdir::WalkDir::new(path)
         .into_iter()
         .par_bridge()
-        .filter_map(|entry| {
-            let Ok(e) = entry else {
-                return None;
-            };
-
-            if e.path().extension().and_then(OsStr::to_str) == Some("org") {
-                Some(e)
-            } else {
-                None
+        .filter_map(|entry| match entry {
+            Ok(e) if is_org_file(e.path()) => Some(read_file(e.path(), options, &now)),
+            Ok(_) => None,
+            // Most likely a directory we aren't allowed into.
+            Err(e) => {
+                let path = e.path().map(Path::to_owned).unwrap_or_default();
+                Some(Parsed::skipped(&path, e.to_string()))
             }
         })
-        .flat_map(|entry| {
-            // Entry is guaranteed to be Ok and to be an org file.
-            // Read entry as str.
-            let data = fs::read_to_string(entry.path()).unwrap();
-
-            // Parse our document.
-            let parse = parse_config.clone().parse(&data);
-
-            let mut traversal = Traversal {
-                items: vec![],
-                stack: vec![],
-                now: now.clone(),
-            };
+        .reduce(Parsed::default, Parsed::merge);
 
-            parse.traverse(&mut traversal);
+    // Files are read in whatever order they finish in.
+    parsed
+        .warnings
+        .sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    parsed.skipped.sort_by(|a, b| a.path.cmp(&b.path));
+    parsed
+}
 
-            let res = traversal.finish();
+/// Like [`parse_file`], but a file that can't be read is skipped rather than failing, so one
+/// bad file doesn't stop the rest from syncing.
+fn read_file(path: &Path, options: &Options, now: &Zoned) -> Parsed {
+    parse_file(path, options, now).unwrap_or_else(|e| Parsed::skipped(path, e.to_string()))
+}
 
-            for item in &res {
-                assert!(!item.timestamps.is_empty());
-            }
+pub fn is_org_file(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str) == Some("org")
+}
 
-            res
-        })
-        .collect()
+/// Reads an org file, and whether it had to be read as Latin-1. Files that aren't UTF-8 are most
+/// likely Latin-1, where every byte is a character.
+pub fn read_text(path: &Path) -> io::Result<(String, bool)> {
+    Ok(match String::from_utf8(fs::read(path)?) {
+        Ok(data) => (data, false),
+        Err(e) => (e.into_bytes().into_iter().map(char::from).collect(), true),
+    })
+}
+
+/// Gets the valid items out of a single org file, as of `now`.
+pub fn parse_file(path: &Path, options: &Options, now: &Zoned) -> io::Result<Parsed> {
+    let parse_config = ParseConfig {
+        todo_keywords: (options.todo_keywords.clone(), options.done_keywords.clone()),
+        ..Default::default()
+    };
+
+    let (data, latin1) = read_text(path)?;
+
+    // Parse our document.
+    let parse = parse_config.parse(&data);
+
+    let mut traversal = Traversal {
+        path: std::path::absolute(path).unwrap_or_else(|_| path.to_owned()),
+        // Like org, default to the file's name until a #+CATEGORY says otherwise.
+        category: path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        file_tags: vec![],
+        options: options.clone(),
+        paragraph_depth: 0,
+        ignored_depth: 0,
+        line_starts: std::iter::once(0)
+            .chain(data.match_indices('\n').map(|(i, _)| i + 1))
+            .collect(),
+        items: vec![],
+        stack: vec![],
+        warnings: vec![],
+        now: now.clone(),
+    };
+    if latin1 {
+        traversal.warnings.push(Warning {
+            path: traversal.path.clone(),
+            line: None,
+            message: "isn't UTF-8, read it as Latin-1".to_string(),
+        });
+    }
+
+    parse.traverse(&mut traversal);
+
+    Ok(traversal.finish())
 }
 
 struct Traversal {
+    path: PathBuf,
+    /// The file's `#+CATEGORY`.
+    category: String,
+    /// The file's `#+FILETAGS`, which every headline inherits.
+    file_tags: Vec<String>,
+    options: Options,
+    /// How many paragraphs we're inside; text in paragraphs makes up an item's body.
+    paragraph_depth: usize,
+    /// How many drawers, blocks or tables in [`Options::ignore`] we're inside.
+    ignored_depth: usize,
+    /// Where each line of the file starts.
+    line_starts: Vec<usize>,
     items: Vec<AgendaItem>,
     stack: Vec<AgendaItem>,
+    warnings: Vec<Warning>,
     now: Zoned,
 }
 
-// This traversal ignores four timestamps:
-// - Timestamps for DONE/CNCL entries
-// - Timestamps for all-day entries
-// - Timestamps after today
+// This traversal ignores seven timestamps:
+// - Timestamps for DONE/CNCL (or whatever `Options::done_keywords` is) entries
+// - Timestamps for all-day entries, unless enabled in `Options`
+// - Timestamps before today
 // - Inactive timestamps
+// - Timestamps that end before they start
+// - Timestamps in drawers, blocks or tables, if they're in `Options::ignore`
+// - Timestamps identical to an earlier one in the same entry, like a SCHEDULED one repeated in
+//   the body
+// CLOCK entries are collected separately, if `Options::clocks` is set, whether the entry is done
+// or not.
 impl Traverser for Traversal {
     fn event(&mut self, event: Event, _ctx: &mut TraversalContext) {
+        if let Event::Enter(c) | Event::Leave(c) = &event {
+            if Context::of(c).is_some_and(|c| self.options.ignore.contains(&c)) {
+                match event {
+                    Event::Enter(_) => self.ignored_depth += 1,
+                    _ => self.ignored_depth -= 1,
+                }
+            }
+        }
+
         match event {
+            // File-level keywords only count before the first headline.
+            Event::Enter(Container::Keyword(k)) if self.stack.is_empty() => {
+                let raw = k.raw();
+                let Some((key, value)) = raw.trim().trim_start_matches("#+").split_once(':') else {
+                    return;
+                };
+
+                match key.to_ascii_uppercase().as_str() {
+                    "CATEGORY" if !value.trim().is_empty() => {
+                        self.category = value.trim().to_string()
+                    }
+                    "FILETAGS" => {
+                        self.file_tags.extend(
+                            value
+                                .split(':')
+                                .map(str::trim)
+                                .filter(|t| !t.is_empty())
+                                .map(str::to_string),
+                        );
+                    }
+                    _ => {}
+                }
+            }
             Event::Enter(Container::Headline(headline)) => {
-                let mut timestamps = vec![];
+                let name = headline.title_raw();
+                let mut outline: Vec<String> = self.stack.iter().map(|i| i.name.clone()).collect();
+                outline.push(name.clone());
 
-                if let Some(p) = headline.planning() {
-                    if let Some(s) = p.scheduled() {
-                        if let Some(ts) = RepeatedDate::from_org(&s, self.now.time_zone().clone()) {
-                            timestamps.push(ts);
-                        }
+                // Tags are inherited from parent headlines, and from the file.
+                let mut tags = self
+                    .stack
+                    .last()
+                    .map(|p| p.tags.clone())
+                    .unwrap_or_else(|| self.file_tags.clone());
+                for t in headline.tags() {
+                    if !tags.iter().any(|o| *o == *t) {
+                        tags.push(t.to_string());
                     }
+                }
 
-                    if let Some(s) = p.deadline() {
-                        if let Some(ts) = RepeatedDate::from_org(&s, self.now.time_zone().clone()) {
-                            timestamps.push(ts);
+                let properties = headline
+                    .properties()
+                    .map(|ps| properties(&ps))
+                    .unwrap_or_default();
+                // So is the category.
+                let category = properties
+                    .get("CATEGORY")
+                    .cloned()
+                    .or_else(|| self.stack.last().map(|p| p.category.clone()))
+                    .unwrap_or_else(|| self.category.clone());
+                // And the time zone.
+                let inherited = self
+                    .stack
+                    .last()
+                    .map_or(&self.options.tz, |p| &p.time_zone)
+                    .clone();
+                let time_zone = match properties.get("TIMEZONE") {
+                    Some(zone) => match TimeZone::get(zone) {
+                        Ok(tz) => tz,
+                        Err(e) => {
+                            self.warn(
+                                headline.text_range().start().into(),
+                                format!("{name}: invalid :TIMEZONE: {zone}: {e}"),
+                            );
+                            inherited
                         }
+                    },
+                    None => inherited,
+                };
+
+                let mut timestamps = vec![];
+                if let Some(p) = headline.planning() {
+                    let planned = [
+                        (p.scheduled(), TimestampKind::Scheduled),
+                        (p.deadline(), TimestampKind::Deadline),
+                    ];
+                    for (s, kind) in planned {
+                        let Some(mut ts) = s.and_then(|s| self.timestamp(&s, &time_zone, &name))
+                        else {
+                            continue;
+                        };
+                        ts.index = timestamps.len();
+                        ts.kind = kind;
+                        timestamps.push(ts);
                     }
                 }
 
                 self.stack.push(AgendaItem {
-                    name: headline.title_raw(),
+                    name,
+                    todo: headline.todo_keyword().map(|k| k.to_string()),
+                    priority: headline.priority().map(|p| p.to_string()),
+                    // Commenting out a headline comments out everything under it.
+                    commented: headline.is_commented()
+                        || self.stack.last().is_some_and(|p| p.commented),
+                    id: properties.get("ID").cloned(),
+                    location: properties.get("LOCATION").cloned(),
+                    category,
+                    time_zone,
+                    properties,
+                    body: String::new(),
+                    path: self.path.clone(),
+                    outline,
+                    tags,
                     timestamps,
+                    clocks: vec![],
+                    reminders: None,
                 });
             }
+            Event::Enter(Container::Paragraph(_)) => self.paragraph_depth += 1,
+            Event::Leave(Container::Paragraph(_)) => {
+                self.paragraph_depth -= 1;
+                if let Some(top) = self.stack.last_mut() {
+                    top.body.push('\n');
+                }
+            }
+            Event::Text(text) if self.paragraph_depth > 0 => {
+                if let Some(top) = self.stack.last_mut() {
+                    top.body.push_str(&text);
+                }
+            }
+            Event::LineBreak(_) if self.paragraph_depth > 0 => {
+                if let Some(top) = self.stack.last_mut() {
+                    top.body.push('\n');
+                }
+            }
             Event::Leave(Container::Headline(headline)) => {
-                let mut l = self.stack.pop().expect("Left headline before entering?");
+                let Some(mut l) = self.stack.pop() else {
+                    return;
+                };
+                l.body = l.body.trim().to_string();
 
-                // Immediately return if we're looking at a DONE/CNCL.
-                if DONE_KEYWORDS
-                    .into_iter()
-                    .any(|k| headline.todo_keyword().as_ref().map(|c| c.as_ref()) == Some(k))
+                // Immediately return if we're looking at a DONE/CNCL, unless time was clocked on it.
+                if l.todo
+                    .as_ref()
+                    .is_some_and(|k| self.options.done_keywords.contains(k))
                 {
+                    if !l.clocks.is_empty() {
+                        l.timestamps.clear();
+                        self.items.push(l);
+                    }
                     return;
                 }
 
+                let until = l.properties.get("REPEAT_UNTIL").and_then(|v| parse_date(v));
+                // How long timestamps without an end of their own last.
+                let at = headline.text_range().start().into();
+                let duration = match l.properties.get("EFFORT") {
+                    Some(v) => match parse_duration(v) {
+                        Some(d) => d,
+                        None => {
+                            self.warn(
+                                at,
+                                format!(
+                                    "{}: invalid :EFFORT: {v}, using the default duration",
+                                    l.name
+                                ),
+                            );
+                            self.options.default_duration
+                        }
+                    },
+                    None => self.options.default_duration,
+                };
+                let count = l
+                    .properties
+                    .get("REPEAT_COUNT")
+                    .and_then(|v| v.parse().ok());
+                l.reminders = match l
+                    .properties
+                    .get("REMINDERS")
+                    .or_else(|| l.properties.get("ALARM"))
+                {
+                    Some(v) => {
+                        let reminders = parse_reminders(v);
+                        if reminders.is_none() {
+                            self.warn(
+                                at,
+                                format!(
+                                    "{}: invalid :REMINDERS: {v}, using the calendar's",
+                                    l.name
+                                ),
+                            );
+                        }
+                        reminders
+                    }
+                    None => None,
+                };
+
+                // Planning lines are seen twice, once as planning and once as plain timestamps,
+                // and the same time can be written more than once. Keep the first of each, so
+                // SCHEDULED and DEADLINE win.
+                let mut seen = HashSet::new();
+                l.timestamps.retain(|ts| seen.insert(ts.identity()));
+
                 // Remove all invalid timestamps
-                l.timestamps.retain_mut(|ts| match &ts.start {
-                    Dateish::AllDay(_) => false,
-                    Dateish::Precise(zoned) => {
-                        if let Some(Dateish::Precise(zoned_end)) = &ts.end {
-                            zoned_end > self.now
-                        } else {
-                            ts.end = Some(Dateish::Precise(
-                                zoned.checked_add(1.hour()).expect("Overflow duration"),
-                            ));
-
-                            zoned > self.now
+                l.timestamps.retain_mut(|ts| {
+                    match &ts.start {
+                        Dateish::AllDay(_) => {
+                            if !self.options.keep_all_day(ts.kind, &l.tags) {
+                                return false;
+                            }
                         }
+                        Dateish::Precise(zoned) => {
+                            if !matches!(ts.end, Some(Dateish::Precise(_))) {
+                                ts.end = zoned
+                                    .checked_add(duration.seconds())
+                                    .ok()
+                                    .map(Dateish::Precise);
+                            }
+                        }
+                    }
+
+                    if ts.end.as_ref().is_some_and(|e| e.is_before(&ts.start)) {
+                        self.warnings.push(Warning {
+                            path: self.path.clone(),
+                            line: Some(ts.line),
+                            message: format!(
+                                "{}: {} ends before it starts, skipping it",
+                                l.name,
+                                ts.raw.trim()
+                            ),
+                        });
+                        return false;
                     }
+
+                    if let Some(rep) = &mut ts.repeat {
+                        rep.until = until;
+                        rep.count = count;
+                    }
+
+                    ts.upcoming(&self.now)
                 });
 
-                if !l.timestamps.is_empty() {
+                if !l.timestamps.is_empty() || !l.clocks.is_empty() {
                     self.items.push(l);
                 }
             }
-            Event::Timestamp(ts) => {
+            Event::Clock(clock) if self.options.clocks => {
+                let line = self.line(clock.text_range().start().into());
                 let Some(top) = self.stack.last_mut() else {
                     return;
                 };
 
-                if ts.is_inactive() {
+                let raw = clock.raw();
+                let Some((start, end)) = parse_clock(&raw, &top.time_zone, self.options.dst) else {
+                    return;
+                };
+                if end.as_ref().is_some_and(|e| *e < start) {
+                    let message = format!(
+                        "{}: {} ends before it starts, skipping it",
+                        top.name,
+                        raw.trim()
+                    );
+                    self.warn(clock.text_range().start().into(), message);
+                    return;
+                }
+
+                top.clocks.push(Clocked {
+                    start,
+                    end,
+                    raw,
+                    range: clock.text_range(),
+                    line,
+                });
+            }
+            Event::Timestamp(ts) => {
+                let Some(top) = self.stack.last() else {
+                    return;
+                };
+
+                if ts.is_inactive() || self.ignored_depth > 0 {
                     return;
                 }
 
-                let Some(t) = RepeatedDate::from_org(&ts, self.now.time_zone().clone()) else {
+                let (tz, name) = (top.time_zone.clone(), top.name.clone());
+                let Some(mut t) = self.timestamp(&ts, &tz, &name) else {
                     return;
                 };
 
+                let top = self.stack.last_mut().expect("checked above");
+                t.index = top.timestamps.len();
                 top.timestamps.push(t);
             }
             _ => {}
@@ -153,37 +602,370 @@ impl Traverser for Traversal {
 }
 
 impl Traversal {
-    fn finish(self) -> Vec<AgendaItem> {
-        self.items
+    fn finish(self) -> Parsed {
+        Parsed {
+            items: self.items,
+            warnings: self.warnings,
+            skipped: vec![],
+        }
+    }
+
+    /// Notes a problem at `offset` that doesn't stop us reading the rest of the file.
+    fn warn(&mut self, offset: usize, message: String) {
+        let line = self.line(offset);
+        self.warnings.push(Warning {
+            path: self.path.clone(),
+            line: Some(line),
+            message,
+        });
+    }
+
+    /// Reads `ts` as being in `tz`, warning about (and skipping) it if it isn't a valid time
+    /// there.
+    fn timestamp(
+        &mut self,
+        ts: &orgize::ast::Timestamp,
+        tz: &TimeZone,
+        name: &str,
+    ) -> Option<RepeatedDate> {
+        let line = self.line(ts.text_range().start().into());
+        match RepeatedDate::from_org(ts, tz, self.options.dst) {
+            Ok(t) => t.map(|t| RepeatedDate { line, ..t }),
+            Err(e) => {
+                self.warn(
+                    ts.text_range().start().into(),
+                    format!("{name}: {}: {e}, skipping it", ts.raw().trim()),
+                );
+                None
+            }
+        }
+    }
+
+    /// The (1-based) line `offset` is on.
+    fn line(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|&start| start <= offset)
+    }
+}
+
+/// Collects the node properties in a property drawer, with upper-cased keys.
+fn properties(props: &PropertyDrawer) -> HashMap<String, String> {
+    props
+        .node_properties()
+        .filter_map(|prop| {
+            let raw = prop.raw();
+            let (k, v) = raw.trim().strip_prefix(':')?.split_once(':')?;
+            let v = v.trim();
+
+            (!v.is_empty()).then(|| (k.to_ascii_uppercase(), v.to_string()))
+        })
+        .collect()
+}
+
+/// Parses the date out of a property value like `2026-12-31` or `<2026-12-31 Thu>`.
+fn parse_date(v: &str) -> Option<Date> {
+    v.trim_matches(['<', '>', '[', ']'])
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Parses a clock line like `CLOCK: [2026-10-14 Wed 09:00]--[2026-10-14 Wed 10:30] =>  1:30`
+/// into its start and, unless it's still running, its end.
+fn parse_clock(raw: &str, tz: &TimeZone, dst: Disambiguation) -> Option<(Zoned, Option<Zoned>)> {
+    let rest = raw.trim().strip_prefix("CLOCK:")?;
+    let rest = rest.split("=>").next()?.trim();
+
+    match rest.split_once("--") {
+        Some((start, end)) => Some((
+            parse_inactive(start, tz, dst)?,
+            Some(parse_inactive(end, tz, dst)?),
+        )),
+        None => Some((parse_inactive(rest, tz, dst)?, None)),
+    }
+}
+
+/// Parses an inactive timestamp with a time, like `[2026-10-14 Wed 09:00]`.
+fn parse_inactive(ts: &str, tz: &TimeZone, dst: Disambiguation) -> Option<Zoned> {
+    let inner = ts.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let mut parts = inner.split_whitespace();
+    let day: Date = parts.next()?.parse().ok()?;
+    // The day of the week is optional, and we don't need it.
+    let (h, m) = parts.find_map(|p| p.split_once(':'))?;
+
+    zoned(day, h.parse().ok()?, m.parse().ok()?, tz, dst).ok()
+}
+
+/// The time `h:m` on `day` in `tz`. Times that happen twice or not at all because of DST
+/// transitions are resolved with `dst`.
+fn zoned(
+    day: Date,
+    h: i8,
+    m: i8,
+    tz: &TimeZone,
+    dst: Disambiguation,
+) -> Result<Zoned, jiff::Error> {
+    let dt = day.to_datetime(Time::new(h, m, 0, 0)?);
+    tz.to_ambiguous_zoned(dt).disambiguate(dst)
+}
+
+/// `day`, at `time` if there is one. Org allows hours past 24 for times early the next morning.
+fn dateish(
+    day: Date,
+    time: Option<(i8, i8)>,
+    tz: &TimeZone,
+    dst: Disambiguation,
+) -> Result<Dateish, jiff::Error> {
+    let Some((h, m)) = time else {
+        return Ok(Dateish::AllDay(day));
+    };
+    let day = if h >= 24 { day.tomorrow()? } else { day };
+
+    Ok(Dateish::Precise(zoned(day, h % 24, m, tz, dst)?))
+}
+
+/// Finds a warning period cookie like `-3d` in the first timestamp of `raw`, in seconds.
+fn warning_period(raw: &str) -> Option<i64> {
+    let first = raw.split(['>', ']']).next()?;
+    first
+        .split_whitespace()
+        .find_map(|t| parse_duration(t.strip_prefix('-')?.trim_start_matches('-')))
+}
+
+/// Finds the end time of a range like `10:00-11:30` in the first timestamp of `raw`.
+fn time_range_end(raw: &str) -> Option<(i8, i8)> {
+    let first = raw.split("--").next()?;
+    first.split_whitespace().find_map(|t| {
+        let (_, end) = t.trim_end_matches(['>', ']']).split_once('-')?;
+        let (h, m) = end.split_once(':')?;
+        Some((h.parse().ok()?, m.parse().ok()?))
+    })
+}
+
+/// Parses an org duration into seconds. Accepts `1:30` (hours and minutes), units like `45min`
+/// or `1.5h` (also `d`, `w`, `m` and `y`), or a combination like `1d 2:00`.
+pub fn parse_duration(s: &str) -> Option<i64> {
+    if s.trim().is_empty() {
+        return None;
+    }
+
+    let mut total: i64 = 0;
+    for part in s.split_whitespace() {
+        let secs = match part.split_once(':') {
+            Some((h, m)) => {
+                let (h, m): (i64, i64) = (h.parse().ok()?, m.parse().ok()?);
+                if h < 0 || !(0..60).contains(&m) {
+                    return None;
+                }
+                h.checked_mul(3600)?.checked_add(m * 60)?
+            }
+            None => {
+                let split = part.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+                let (n, unit) = part.split_at(split);
+                let n: f64 = n.parse().ok()?;
+                let unit = match unit {
+                    "min" => 60,
+                    "h" => 3600,
+                    "d" => 86400,
+                    "w" => 7 * 86400,
+                    "m" => 30 * 86400,
+                    "y" => 365 * 86400,
+                    _ => return None,
+                };
+                (n * unit as f64).round() as i64
+            }
+        };
+        total = total.checked_add(secs)?;
     }
+
+    // Longer than a span can be is certainly a typo, and would overflow later on.
+    Span::new().try_seconds(total).ok()?;
+    Some(total)
+}
+
+/// How a calendar reminds you of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReminderMethod {
+    Popup,
+    Email,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reminder {
+    pub method: ReminderMethod,
+    /// How long before the event, in minutes.
+    pub minutes: i64,
+}
+
+/// Parses a list of reminders like `10min, email 1d`. Each is a duration before the event (see
+/// [`parse_duration`]), sent as a `popup` unless it says `email`. `none` means no reminders at
+/// all.
+pub fn parse_reminders(s: &str) -> Option<Vec<Reminder>> {
+    if s.trim().eq_ignore_ascii_case("none") {
+        return Some(vec![]);
+    }
+
+    s.split(',')
+        .map(|r| {
+            let mut method = ReminderMethod::Popup;
+            let mut duration = vec![];
+            for word in r.split_whitespace() {
+                match word.to_ascii_lowercase().as_str() {
+                    "popup" => method = ReminderMethod::Popup,
+                    "email" => method = ReminderMethod::Email,
+                    _ => duration.push(word),
+                }
+            }
+
+            Some(Reminder {
+                method,
+                minutes: parse_duration(&duration.join(" "))? / 60,
+            })
+        })
+        .collect()
+}
+
+/// A stable, dependency-free hash (64-bit FNV-1a) of a sequence of byte strings, as hex.
+///
+/// Unlike `DefaultHasher`, the output of this is guaranteed not to change between runs or
+/// compiler versions, so it's safe to persist.
+pub fn stable_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET;
+    for part in parts {
+        for b in part.iter().chain(&[0]) {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+
+    format!("{hash:016x}")
 }
 
 #[derive(Debug, Clone)]
 pub struct AgendaItem {
     pub name: String,
+    pub todo: Option<String>,
+    /// The letter in the headline's priority cookie, like `A` for `[#A]`.
+    pub priority: Option<String>,
+    /// Whether this headline, or one of its parents, is a `COMMENT` headline.
+    pub commented: bool,
+    /// The headline's `:ID:` property.
+    pub id: Option<String>,
+    /// All of the headline's properties, keyed by upper-cased name.
+    pub properties: HashMap<String, String>,
+    /// The text of the headline's section, without any markup.
+    pub body: String,
+    /// The headline's `:LOCATION:` property.
+    pub location: Option<String>,
+    /// The headline's `:CATEGORY:`, inherited from its parents or the file's `#+CATEGORY`, or
+    /// failing that the file's name.
+    pub category: String,
+    /// What zone the headline's timestamps are in: its `:TIMEZONE:`, inherited from its
+    /// parents, or failing that [`Options::tz`].
+    pub time_zone: TimeZone,
+    /// The org file this headline lives in, as an absolute path.
+    pub path: PathBuf,
+    /// The titles of every headline from the top of the file down to (and including) this one.
+    pub outline: Vec<String>,
+    /// This headline's tags, including inherited ones.
+    pub tags: Vec<String>,
     pub timestamps: Vec<RepeatedDate>,
+    /// Time clocked on this headline, if [`Options::clocks`] is set.
+    pub clocks: Vec<Clocked>,
+    /// From the headline's `:REMINDERS:` (or `:ALARM:`) property. See [`parse_reminders`].
+    pub reminders: Option<Vec<Reminder>>,
 }
 
+/// A `CLOCK:` entry.
 #[derive(Debug, Clone)]
-pub struct RepeatedDate {
-    start: Dateish,
-    end: Option<Dateish>,
-    repeat: Option<String>,
+pub struct Clocked {
+    pub start: Zoned,
+    /// Missing if the clock is still running.
+    pub end: Option<Zoned>,
+    /// The clock line as written in the file, and where it is.
+    pub raw: String,
+    pub range: TextRange,
+    /// The (1-based) line the clock is on.
+    pub line: usize,
 }
 
-impl RepeatedDate {
-    pub fn into_gcal(self) -> (EventDateTime, Option<EventDateTime>, Option<String>) {
-        let start = self.start.into_gcal();
-        let end = self.end.map(|e| e.into_gcal());
+impl AgendaItem {
+    /// Whether this headline is in an archived tree, one tagged `:ARCHIVE:`.
+    pub fn archived(&self) -> bool {
+        self.tags.iter().any(|t| t == "ARCHIVE")
+    }
 
-        let rep = self.repeat;
+    /// A link that opens this headline in Emacs.
+    pub fn link(&self) -> String {
+        match &self.id {
+            Some(id) => format!("org-protocol://org-id?id={id}"),
+            None => format!("file://{}::*{}", self.path.to_string_lossy(), self.name),
+        }
+    }
 
-        (start, end, rep)
+    /// Returns a key for one of this item's timestamps that stays the same between runs as long
+    /// as the headline isn't moved or retitled (or at all, if the headline has an `:ID:`).
+    pub fn key(&self, ts: &RepeatedDate) -> String {
+        format!("{}/{}", self.key_base(), ts.index)
     }
+
+    /// Like [`AgendaItem::key`], for a clock entry. New entries go above old ones, so these are
+    /// keyed by when they started rather than where they are.
+    pub fn clock_key(&self, clock: &Clocked) -> String {
+        format!("{}/clock/{}", self.key_base(), clock.start.timestamp())
+    }
+
+    fn key_base(&self) -> String {
+        match &self.id {
+            Some(id) => id.clone(),
+            None => {
+                let path = self.path.to_string_lossy();
+                let parts = std::iter::once(path.as_bytes())
+                    .chain(self.outline.iter().map(|o| o.as_bytes()));
+
+                stable_hash(parts)
+            }
+        }
+    }
+}
+
+/// Where a timestamp was in its headline, and what it looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampKind {
+    Scheduled,
+    Deadline,
+    /// An active timestamp anywhere else in the entry.
+    Plain,
+    /// A plain timestamp with an end, like `<2026-10-20 Tue>--<2026-10-22 Thu>` or
+    /// `<2026-10-20 Tue 10:00-11:30>`.
+    Range,
+}
+
+#[derive(Debug, Clone)]
+pub struct RepeatedDate {
+    pub start: Dateish,
+    pub end: Option<Dateish>,
+    pub repeat: Option<Repeater>,
+    pub kind: TimestampKind,
+    /// Whether the timestamp itself has an end, as opposed to one we filled in.
+    pub has_end: bool,
+    /// A `-3d` cookie, in seconds. On deadlines it's how far ahead to be warned; on anything
+    /// else it doesn't mean that, so it's ignored.
+    pub warning: Option<i64>,
+    /// The timestamp's original text and where it is in its file.
+    pub raw: String,
+    pub range: TextRange,
+    /// The (1-based) line the timestamp is on.
+    pub line: usize,
+    /// The position of this timestamp within its headline, before any filtering.
+    index: usize,
 }
 
 impl Dateish {
-    fn into_gcal(self) -> EventDateTime {
+    pub fn into_gcal(self) -> EventDateTime {
         match self {
             Dateish::AllDay(date) => EventDateTime {
                 date: Some(
@@ -195,7 +977,8 @@ impl Dateish {
                     .unwrap(),
                 ),
                 date_time: None,
-                time_zone: TimeZone::system().iana_name().unwrap().to_string(),
+                // All-day events don't have a zone.
+                time_zone: String::new(),
             },
             Dateish::Precise(zoned) => {
                 let utc = zoned
@@ -205,7 +988,9 @@ impl Dateish {
                 EventDateTime {
                     date: None,
                     date_time: Some(chrono::DateTime::from_timestamp_nanos(utc as i64)),
-                    time_zone: TimeZone::system().iana_name().unwrap().to_string(),
+                    // Only matters for working out when repeats happen. Zones without a name
+                    // are rare enough that repeating in UTC will do.
+                    time_zone: zoned.time_zone().iana_name().unwrap_or("UTC").to_string(),
                 }
             }
         }
@@ -218,82 +1003,592 @@ pub enum Dateish {
     Precise(Zoned),
 }
 
-impl RepeatedDate {
-    fn from_org(ts: &orgize::ast::Timestamp, tz: TimeZone) -> Option<Self> {
-        let year_start = ts.year_start()?.parse().ok()?;
-        let month_start = ts.month_start()?.parse().ok()?;
-        let day_start = ts.day_start()?.parse().ok()?;
+impl Dateish {
+    /// Converts a date from Google Calendar back into a `Dateish`.
+    pub fn from_gcal(edt: &EventDateTime) -> Option<Self> {
+        if let Some(dt) = edt.date_time {
+            let ts = jiff::Timestamp::from_nanosecond(dt.timestamp_nanos_opt()? as i128).ok()?;
+            let tz = TimeZone::get(&edt.time_zone).unwrap_or_else(|_| TimeZone::system());
 
-        let mut s = date(year_start, month_start, day_start);
+            Some(Dateish::Precise(ts.to_zoned(tz)))
+        } else {
+            let d = edt.date?;
+            let d = Date::new(d.year() as i16, d.month() as i8, d.day() as i8).ok()?;
 
-        let hour_start = ts.hour_start().and_then(|h| h.parse().ok());
-        let min_start = ts.minute_start().and_then(|m| m.parse().ok());
+            Some(Dateish::AllDay(d))
+        }
+    }
 
-        let sish = if let (Some(mut h), Some(m)) = (hour_start, min_start) {
-            if h >= 24 {
-                h %= 24;
-                s = s
-                    .checked_add(1.day())
-                    .expect("Adding one day overflowed date");
-            }
-            Dateish::Precise(
-                s.at(h, m, 0, 0)
-                    .to_zoned(tz.clone())
-                    .expect("Timezone bullshit"),
-            )
-        } else {
-            Dateish::AllDay(s)
+    /// A representation that's the same for two dates exactly when they refer to the same
+    /// day or instant, regardless of what time zone they're in.
+    pub fn canonical(&self) -> String {
+        match self {
+            Dateish::AllDay(date) => date.to_string(),
+            Dateish::Precise(zoned) => zoned.timestamp().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Dateish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dateish::AllDay(date) => write!(f, "{date}"),
+            Dateish::Precise(zoned) => write!(f, "{zoned}"),
+        }
+    }
+}
+
+impl RepeatedDate {
+    /// Reads `ts` as being in `tz`. `None` if it isn't a timestamp we understand, and an error
+    /// if it's one that doesn't exist, like the 30th of February, or a time DST skips over when
+    /// `dst` is [`Disambiguation::Reject`].
+    fn from_org(
+        ts: &orgize::ast::Timestamp,
+        tz: &TimeZone,
+        dst: Disambiguation,
+    ) -> Result<Option<Self>, jiff::Error> {
+        let Some((year_start, month_start, day_start)) = (|| {
+            Some((
+                ts.year_start()?.parse().ok()?,
+                ts.month_start()?.parse().ok()?,
+                ts.day_start()?.parse().ok()?,
+            ))
+        })() else {
+            return Ok(None);
         };
 
-        let year_end = ts.year_end()?.parse().ok()?;
-        let month_end = ts.month_end()?.parse().ok()?;
-        let day_end = ts.day_end()?.parse().ok()?;
+        let hour_start = ts.hour_start().and_then(|h| h.parse().ok());
+        let min_start = ts.minute_start().and_then(|m| m.parse().ok());
+        let sish = dateish(
+            Date::new(year_start, month_start, day_start)?,
+            hour_start.zip(min_start),
+            tz,
+            dst,
+        )?;
 
         let eish = if ts.is_range() {
-            let mut e = date(year_end, month_end, day_end);
+            let Some((year_end, month_end, day_end)) = (|| {
+                Some((
+                    ts.year_end()?.parse().ok()?,
+                    ts.month_end()?.parse().ok()?,
+                    ts.day_end()?.parse().ok()?,
+                ))
+            })() else {
+                return Ok(None);
+            };
 
             let hour_end = ts.hour_end().and_then(|h| h.parse().ok());
             let min_end = ts.minute_end().and_then(|m| m.parse().ok());
-
-            let eish = if let (Some(mut h), Some(m)) = (hour_end, min_end) {
-                if h >= 24 {
-                    h %= 24;
-                    e = e
-                        .checked_add(1.day())
-                        .expect("Adding one day overflowed date");
-                }
-                Dateish::Precise(
-                    e.at(h, m, 0, 0)
-                        .to_zoned(tz.clone())
-                        .expect("Timezone bullshit"),
-                )
-            } else {
-                Dateish::AllDay(e)
-            };
-
-            Some(eish)
+            Some(dateish(
+                Date::new(year_end, month_end, day_end)?,
+                hour_end.zip(min_end),
+                tz,
+                dst,
+            )?)
         } else {
             None
         };
 
+        // `<2026-10-20 Tue 10:00-11:30>`: a time range within a single timestamp.
+        let eish = match (&sish, eish) {
+            (Dateish::Precise(start), None) => match time_range_end(&ts.raw()) {
+                Some(hm) => Some(dateish(start.date(), Some(hm), tz, dst)?),
+                None => None,
+            },
+            (_, eish) => eish,
+        };
+
         let repeat = if let (Some(unit), Some(int)) = (ts.repeater_unit(), ts.repeater_value()) {
             let freq = match unit {
-                orgize::ast::TimeUnit::Hour => "HOURLY",
-                orgize::ast::TimeUnit::Day => "DAILY",
-                orgize::ast::TimeUnit::Week => "WEEKLY",
-                orgize::ast::TimeUnit::Month => "MONTHLY",
-                orgize::ast::TimeUnit::Year => "YEARLY",
+                orgize::ast::TimeUnit::Hour => Freq::Hourly,
+                orgize::ast::TimeUnit::Day => Freq::Daily,
+                orgize::ast::TimeUnit::Week => Freq::Weekly,
+                orgize::ast::TimeUnit::Month => Freq::Monthly,
+                orgize::ast::TimeUnit::Year => Freq::Yearly,
             };
 
-            Some(format!("RRULE:FREQ={freq};INTERVAL={}", int))
+            Some(Repeater {
+                kind: RepeaterKind::from_raw(&ts.raw()),
+                freq,
+                interval: int,
+                until: None,
+                count: None,
+            })
         } else {
             None
         };
 
-        Some(Self {
+        let kind = if eish.is_some() {
+            TimestampKind::Range
+        } else {
+            TimestampKind::Plain
+        };
+
+        Ok(Some(Self {
             start: sish,
+            has_end: eish.is_some(),
             end: eish,
+            warning: warning_period(&ts.raw()),
             repeat,
-        })
+            kind,
+            raw: ts.raw(),
+            range: ts.text_range(),
+            line: 0,
+            index: 0,
+        }))
+    }
+}
+
+/// The three kinds of org repeaters, which differ in where the next occurrence goes once the
+/// current one is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RepeaterKind {
+    /// `+1w`: occurrences stay on the original schedule.
+    Cumulate,
+    /// `++1w`: the next occurrence is the first one on the original schedule that's still
+    /// upcoming.
+    CatchUp,
+    /// `.+1w`: the next occurrence is one interval from today.
+    Restart,
+}
+
+impl RepeaterKind {
+    /// Finds the repeater cookie in a timestamp like `<2026-10-20 Tue 10:00 .+1w>`.
+    fn from_raw(raw: &str) -> Self {
+        let first = raw.split("--<").next().unwrap_or(raw);
+        let cookie = first
+            .trim_matches(['<', '>', '[', ']'])
+            .split_whitespace()
+            .find(|t| t.starts_with(['+', '.']));
+
+        match cookie {
+            Some(c) if c.starts_with("++") => RepeaterKind::CatchUp,
+            Some(c) if c.starts_with(".+") => RepeaterKind::Restart,
+            _ => RepeaterKind::Cumulate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Freq {
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug, Clone)]
+pub struct Repeater {
+    pub kind: RepeaterKind,
+    pub freq: Freq,
+    pub interval: u32,
+    /// The last day the series can have an occurrence on, from `:REPEAT_UNTIL:`.
+    pub until: Option<Date>,
+    /// How many occurrences there are left, from `:REPEAT_COUNT:`.
+    pub count: Option<u32>,
+}
+
+/// How far we're willing to step through a series looking for the next occurrence.
+const MAX_OCCURRENCES: i64 = 100_000;
+
+impl Repeater {
+    /// The offset of the `n`th occurrence from the first.
+    fn span(&self, n: i64) -> Option<Span> {
+        let n = n.checked_mul(i64::from(self.interval))?;
+        let span = match self.freq {
+            Freq::Hourly => Span::new().try_hours(n),
+            Freq::Daily => Span::new().try_days(n),
+            Freq::Weekly => Span::new().try_weeks(n),
+            Freq::Monthly => Span::new().try_months(n),
+            Freq::Yearly => Span::new().try_years(n),
+        };
+
+        span.ok()
+    }
+
+    /// Renders this as an `RRULE:...` line for a series starting at `start`.
+    pub fn rrule(&self, start: &Dateish) -> String {
+        let freq = match self.freq {
+            Freq::Hourly => "HOURLY",
+            Freq::Daily => "DAILY",
+            Freq::Weekly => "WEEKLY",
+            Freq::Monthly => "MONTHLY",
+            Freq::Yearly => "YEARLY",
+        };
+        let mut rule = format!("RRULE:FREQ={freq};INTERVAL={}", self.interval);
+
+        // COUNT and UNTIL can't both be given, so COUNT wins.
+        if let Some(count) = self.count {
+            rule += &format!(";COUNT={count}");
+        } else if let Some(until) = self.until {
+            match start {
+                Dateish::AllDay(_) => rule += &format!(";UNTIL={}", until.strftime("%Y%m%d")),
+                // With a time, UNTIL has to be in UTC. Include all of the last day.
+                Dateish::Precise(z) => {
+                    if let Ok(end) = until.at(23, 59, 59, 0).to_zoned(z.time_zone().clone()) {
+                        let utc = end.with_time_zone(TimeZone::UTC);
+                        rule += &format!(";UNTIL={}", utc.strftime("%Y%m%dT%H%M%SZ"));
+                    }
+                }
+            }
+        }
+
+        rule
+    }
+}
+
+impl RepeatedDate {
+    /// What makes two timestamps the same event: when they happen, and how they repeat.
+    fn identity(&self) -> (String, Option<String>, Option<(RepeaterKind, Freq, u32)>) {
+        (
+            self.start.canonical(),
+            self.end.as_ref().map(Dateish::canonical),
+            self.repeat.as_ref().map(|r| (r.kind, r.freq, r.interval)),
+        )
+    }
+
+    /// The `n`th occurrence of this timestamp's series.
+    fn occurrence(&self, n: i64) -> Option<(Dateish, Option<Dateish>)> {
+        let span = self.repeat.as_ref()?.span(n)?;
+        let start = self.start.checked_add(span)?;
+        let end = match &self.end {
+            Some(e) => Some(e.checked_add(span)?),
+            None => None,
+        };
+
+        Some((start, end))
+    }
+
+    /// Moves a repeating timestamp to the occurrence org would show next, according to its
+    /// repeater, and returns whether there's anything left of it that hasn't ended yet.
+    fn upcoming(&mut self, now: &Zoned) -> bool {
+        let Some(rep) = self.repeat.clone() else {
+            return !has_ended(&self.start, self.end.as_ref(), now);
+        };
+
+        if has_ended(&self.start, self.end.as_ref(), now) {
+            match rep.kind {
+                RepeaterKind::Cumulate => {}
+                RepeaterKind::CatchUp => {
+                    let Some(n) = (1..MAX_OCCURRENCES).find(|n| {
+                        self.occurrence(*n)
+                            .is_some_and(|(s, e)| !has_ended(&s, e.as_ref(), now))
+                    }) else {
+                        return false;
+                    };
+                    let Some((start, end)) = self.occurrence(n) else {
+                        return false;
+                    };
+
+                    self.start = start;
+                    self.end = end;
+                    // Skipped occurrences count towards the total.
+                    if let Some(count) = &mut self.repeat.as_mut().expect("checked above").count {
+                        *count = count.saturating_sub(n as u32);
+                    }
+                }
+                RepeaterKind::Restart => {
+                    // Pretend the series started today, then take the first occurrence after.
+                    let Some(today) = self.start.on(now.date()) else {
+                        return false;
+                    };
+                    let shift = self.start.until(&today);
+                    self.start = today;
+                    self.end = self.end.as_ref().and_then(|e| e.checked_add(shift?));
+
+                    let Some(n) = (1..MAX_OCCURRENCES).find(|n| {
+                        self.occurrence(*n)
+                            .is_some_and(|(s, e)| !has_ended(&s, e.as_ref(), now))
+                    }) else {
+                        return false;
+                    };
+                    let Some((start, end)) = self.occurrence(n) else {
+                        return false;
+                    };
+
+                    self.start = start;
+                    self.end = end;
+                }
+            }
+        }
+
+        let rep = self.repeat.as_ref().expect("checked above");
+        if let Some(count) = rep.count {
+            if count == 0 {
+                return false;
+            }
+
+            // The series is over once its last occurrence is.
+            return self
+                .occurrence(i64::from(count) - 1)
+                .is_some_and(|(s, e)| !has_ended(&s, e.as_ref(), now));
+        }
+
+        if let Some(until) = rep.until {
+            // There has to be an occurrence that hasn't ended, on or before the last day.
+            return (0..MAX_OCCURRENCES)
+                .map_while(|n| self.occurrence(n))
+                .take_while(|(s, _)| s.date() <= until)
+                .any(|(s, e)| !has_ended(&s, e.as_ref(), now));
+        }
+
+        true
+    }
+}
+
+/// Whether an event from `start` to `end` is entirely in the past.
+fn has_ended(start: &Dateish, end: Option<&Dateish>, now: &Zoned) -> bool {
+    match end.unwrap_or(start) {
+        Dateish::AllDay(last) => *last < now.date(),
+        Dateish::Precise(end) => end <= now,
+    }
+}
+
+impl Dateish {
+    /// Whether `self` is earlier than `other`. If either is all-day, only the dates count.
+    fn is_before(&self, other: &Dateish) -> bool {
+        match (self, other) {
+            (Dateish::Precise(a), Dateish::Precise(b)) => a.timestamp() < b.timestamp(),
+            _ => self.date() < other.date(),
+        }
+    }
+
+    fn date(&self) -> Date {
+        match self {
+            Dateish::AllDay(d) => *d,
+            Dateish::Precise(z) => z.date(),
+        }
+    }
+
+    fn checked_add(&self, span: Span) -> Option<Self> {
+        match self {
+            Dateish::AllDay(d) => d.checked_add(span).ok().map(Dateish::AllDay),
+            Dateish::Precise(z) => z.checked_add(span).ok().map(Dateish::Precise),
+        }
+    }
+
+    /// The same time of day, on a different day.
+    fn on(&self, day: Date) -> Option<Self> {
+        match self {
+            Dateish::AllDay(_) => Some(Dateish::AllDay(day)),
+            Dateish::Precise(z) => day
+                .to_datetime(z.time())
+                .to_zoned(z.time_zone().clone())
+                .ok()
+                .map(Dateish::Precise),
+        }
+    }
+
+    /// The span from `self` to `other`, in days (or seconds, for precise times).
+    fn until(&self, other: &Self) -> Option<Span> {
+        match (self, other) {
+            (Dateish::AllDay(a), Dateish::AllDay(b)) => a.until(*b).ok(),
+            (Dateish::Precise(a), Dateish::Precise(b)) => {
+                let secs = b.timestamp().as_second() - a.timestamp().as_second();
+                Span::new().try_seconds(secs).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::civil::date;
+
+    use super::*;
+
+    /// A Wednesday afternoon.
+    fn now() -> Zoned {
+        date(2026, 10, 14)
+            .at(12, 0, 0, 0)
+            .to_zoned(TimeZone::UTC)
+            .unwrap()
+    }
+
+    fn weekly(kind: RepeaterKind) -> Repeater {
+        Repeater {
+            kind,
+            freq: Freq::Weekly,
+            interval: 1,
+            until: None,
+            count: None,
+        }
+    }
+
+    /// A series repeating with `repeat`, from `start`.
+    fn series(start: Dateish, repeat: Repeater) -> RepeatedDate {
+        RepeatedDate {
+            start,
+            end: None,
+            repeat: Some(repeat),
+            kind: TimestampKind::Plain,
+            has_end: false,
+            warning: None,
+            raw: String::new(),
+            range: TextRange::default(),
+            line: 1,
+            index: 0,
+        }
+    }
+
+    /// An all-day series repeating with `repeat`, from the 1st of October.
+    fn from_the_first(repeat: Repeater) -> RepeatedDate {
+        series(Dateish::AllDay(date(2026, 10, 1)), repeat)
+    }
+
+    fn berlin(day: Date, h: i8) -> Dateish {
+        let tz = TimeZone::get("Europe/Berlin").unwrap();
+        Dateish::Precise(day.at(h, 0, 0, 0).to_zoned(tz).unwrap())
+    }
+
+    #[test]
+    fn cumulating_series_keep_their_start() {
+        // Every occurrence is still on the original schedule, so the series starts where it did.
+        let mut ts = from_the_first(weekly(RepeaterKind::Cumulate));
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, Dateish::AllDay(date(2026, 10, 1)));
+    }
+
+    #[test]
+    fn catching_up_skips_to_the_next_occurrence() {
+        // The 8th is over, so the 15th is next.
+        let mut ts = from_the_first(weekly(RepeaterKind::CatchUp));
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, Dateish::AllDay(date(2026, 10, 15)));
+
+        // Today's isn't over yet.
+        let mut ts = series(
+            Dateish::AllDay(date(2026, 9, 30)),
+            weekly(RepeaterKind::CatchUp),
+        );
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, Dateish::AllDay(date(2026, 10, 14)));
+    }
+
+    #[test]
+    fn restarting_goes_from_today() {
+        let mut ts = from_the_first(weekly(RepeaterKind::Restart));
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, Dateish::AllDay(date(2026, 10, 21)));
+
+        // Times of day are kept.
+        let mut ts = series(berlin(date(2026, 10, 1), 9), weekly(RepeaterKind::Restart));
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, berlin(date(2026, 10, 21), 9));
+    }
+
+    #[test]
+    fn counts_run_out() {
+        // The 1st, 8th and 15th, the last of which is still to come.
+        let mut ts = from_the_first(Repeater {
+            count: Some(3),
+            ..weekly(RepeaterKind::Cumulate)
+        });
+        assert!(ts.upcoming(&now()));
+
+        // The 1st and 8th, which are both over.
+        let mut ts = from_the_first(Repeater {
+            count: Some(2),
+            ..weekly(RepeaterKind::Cumulate)
+        });
+        assert!(!ts.upcoming(&now()));
+
+        // Catching up skips occurrences, which count towards the total.
+        let mut ts = from_the_first(Repeater {
+            count: Some(3),
+            ..weekly(RepeaterKind::CatchUp)
+        });
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, Dateish::AllDay(date(2026, 10, 15)));
+        assert_eq!(ts.repeat.unwrap().count, Some(1));
+
+        let mut ts = from_the_first(Repeater {
+            count: Some(2),
+            ..weekly(RepeaterKind::CatchUp)
+        });
+        assert!(!ts.upcoming(&now()));
+
+        let mut ts = from_the_first(Repeater {
+            count: Some(0),
+            ..weekly(RepeaterKind::Cumulate)
+        });
+        assert!(!ts.upcoming(&now()));
+    }
+
+    #[test]
+    fn series_end_on_their_last_day() {
+        // The last day has an occurrence, which is still to come.
+        let mut ts = from_the_first(Repeater {
+            until: Some(date(2026, 10, 15)),
+            ..weekly(RepeaterKind::Cumulate)
+        });
+        assert!(ts.upcoming(&now()));
+
+        // The last occurrence before the last day is over.
+        let mut ts = from_the_first(Repeater {
+            until: Some(date(2026, 10, 14)),
+            ..weekly(RepeaterKind::Cumulate)
+        });
+        assert!(!ts.upcoming(&now()));
+
+        let mut ts = series(
+            berlin(date(2026, 10, 1), 9),
+            Repeater {
+                until: Some(date(2026, 10, 15)),
+                ..weekly(RepeaterKind::CatchUp)
+            },
+        );
+        assert!(ts.upcoming(&now()));
+        assert_eq!(ts.start, berlin(date(2026, 10, 15), 9));
+    }
+
+    #[test]
+    fn renders_rrules() {
+        let day = Dateish::AllDay(date(2026, 10, 1));
+        let every_other_week = Repeater {
+            interval: 2,
+            ..weekly(RepeaterKind::Cumulate)
+        };
+        assert_eq!(every_other_week.rrule(&day), "RRULE:FREQ=WEEKLY;INTERVAL=2");
+
+        let monthly = Repeater {
+            freq: Freq::Monthly,
+            ..weekly(RepeaterKind::Cumulate)
+        };
+        assert_eq!(monthly.rrule(&day), "RRULE:FREQ=MONTHLY;INTERVAL=1");
+
+        let counted = Repeater {
+            count: Some(3),
+            ..weekly(RepeaterKind::Cumulate)
+        };
+        assert_eq!(counted.rrule(&day), "RRULE:FREQ=WEEKLY;INTERVAL=1;COUNT=3");
+
+        // COUNT and UNTIL can't both be given.
+        let both = Repeater {
+            until: Some(date(2026, 10, 15)),
+            ..counted
+        };
+        assert_eq!(both.rrule(&day), "RRULE:FREQ=WEEKLY;INTERVAL=1;COUNT=3");
+    }
+
+    #[test]
+    fn renders_until_in_utc_with_a_time() {
+        let until = Repeater {
+            until: Some(date(2026, 10, 15)),
+            ..weekly(RepeaterKind::Cumulate)
+        };
+        let day = Dateish::AllDay(date(2026, 10, 1));
+        assert_eq!(
+            until.rrule(&day),
+            "RRULE:FREQ=WEEKLY;INTERVAL=1;UNTIL=20261015"
+        );
+
+        // The end of the 15th in Berlin, which is still summer time.
+        assert_eq!(
+            until.rrule(&berlin(date(2026, 10, 1), 9)),
+            "RRULE:FREQ=WEEKLY;INTERVAL=1;UNTIL=20261015T215959Z"
+        );
     }
 }
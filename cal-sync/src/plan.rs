@@ -0,0 +1,167 @@
+//! Printing a [`Plan`] for `--plan`, either for people or as JSON.
+
+use std::collections::BTreeMap;
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::{
+    backend::{RemoteEvent, SyncEvent},
+    org::Dateish,
+    sync::Plan,
+};
+
+#[derive(Debug, Serialize)]
+struct CalendarPlan {
+    create: Vec<Change>,
+    update: Vec<Change>,
+    delete: Vec<Change>,
+    /// Timestamps that would be moved in the org files.
+    write_back: Vec<Change>,
+    conflicts: Vec<Change>,
+    unchanged: usize,
+}
+
+/// One event that would change. Times are RFC 9557 timestamps or plain dates.
+#[derive(Debug, Serialize)]
+struct Change {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// The remote event's id, for updates and deletes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    /// What the times are now, for updates and write-backs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_start: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_end: Option<String>,
+    /// Where in the org files the event comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+}
+
+/// Prints the plan for every calendar, keyed by the calendar's name in the config.
+pub fn print(plans: &BTreeMap<String, Plan>, json: bool) -> Result<()> {
+    let plans: BTreeMap<&str, CalendarPlan> = plans
+        .iter()
+        .map(|(id, plan)| (id.as_str(), calendar_plan(plan)))
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plans)?);
+        return Ok(());
+    }
+
+    for (id, plan) in &plans {
+        println!("{id}:");
+        for (mark, changes) in [
+            ("+", &plan.create),
+            ("~", &plan.update),
+            ("-", &plan.delete),
+            ("<", &plan.write_back),
+            ("!", &plan.conflicts),
+        ] {
+            for c in changes {
+                println!("  {mark} {}", describe(c));
+            }
+        }
+        println!(
+            "  -{} +{} ~{} ({} unchanged)",
+            plan.delete.len(),
+            plan.create.len(),
+            plan.update.len(),
+            plan.unchanged
+        );
+    }
+
+    Ok(())
+}
+
+fn calendar_plan(plan: &Plan) -> CalendarPlan {
+    CalendarPlan {
+        create: plan.inserts.iter().map(local).collect(),
+        update: plan
+            .updates
+            .iter()
+            .map(|(old, ev)| Change {
+                id: Some(old.id.clone()),
+                old_start: old.start.as_ref().map(Dateish::to_string),
+                old_end: old.end.as_ref().map(Dateish::to_string),
+                ..local(ev)
+            })
+            .collect(),
+        delete: plan.deletes.iter().map(remote).collect(),
+        write_back: plan
+            .edits
+            .iter()
+            .map(|e| Change {
+                title: e.origin.raw.trim().to_string(),
+                key: None,
+                id: None,
+                start: Some(e.start.to_string()),
+                end: e.end.as_ref().map(Dateish::to_string),
+                old_start: None,
+                old_end: None,
+                file: Some(e.origin.path.to_string_lossy().into_owned()),
+                line: Some(e.origin.line),
+            })
+            .collect(),
+        conflicts: plan.conflicts.iter().map(local).collect(),
+        unchanged: plan.unchanged.len(),
+    }
+}
+
+/// An event we generated from the org files.
+fn local(ev: &SyncEvent) -> Change {
+    Change {
+        title: ev.summary.clone(),
+        key: Some(ev.key.clone()),
+        id: None,
+        start: Some(ev.start.to_string()),
+        end: ev.end.as_ref().map(Dateish::to_string),
+        old_start: None,
+        old_end: None,
+        file: Some(ev.origin.path.to_string_lossy().into_owned()),
+        line: Some(ev.origin.line),
+    }
+}
+
+/// An event that's only in the calendar.
+fn remote(ev: &RemoteEvent) -> Change {
+    Change {
+        title: ev.summary.clone(),
+        key: ev.key.clone(),
+        id: Some(ev.id.clone()),
+        start: ev.start.as_ref().map(Dateish::to_string),
+        end: ev.end.as_ref().map(Dateish::to_string),
+        old_start: None,
+        old_end: None,
+        file: None,
+        line: None,
+    }
+}
+
+/// A one-line summary of a change, like `Standup 2026-10-17T10:00 (work.org:4)`.
+fn describe(c: &Change) -> String {
+    let mut out = c.title.clone();
+    if let (Some(old), Some(_)) = (&c.old_start, &c.start) {
+        out.push_str(&format!(" {old} ->"));
+    }
+    if let Some(start) = &c.start {
+        out.push_str(&format!(" {start}"));
+    }
+    match (&c.file, c.line) {
+        (Some(file), Some(line)) => out.push_str(&format!(" ({file}:{line})")),
+        (Some(file), None) => out.push_str(&format!(" ({file})")),
+        _ => {}
+    }
+
+    out
+}
@@ -0,0 +1,226 @@
+//! What a run did, for status bars and monitoring: a JSON report with `--format json`, and an
+//! exit code that says what, if anything, went wrong.
+
+use std::{collections::BTreeMap, fmt};
+
+use color_eyre::Report;
+use jiff::Timestamp;
+use serde::Serialize;
+
+use crate::{
+    org::Parsed,
+    sync::{Counts, Outcome},
+};
+
+/// Marks an error as happening while signing in, which gets its own exit code.
+#[derive(Debug)]
+pub struct AuthFailed;
+
+impl fmt::Display for AuthFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Couldn't sign in")
+    }
+}
+
+/// Marks an error as being about org files, with `strict`.
+#[derive(Debug)]
+pub struct OrgFailed;
+
+impl fmt::Display for OrgFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not syncing, since org files had problems")
+    }
+}
+
+/// How a run went, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// Some org files couldn't be read or had problems, like invalid timestamps. Everything
+    /// else was synced, unless `strict` is set.
+    OrgProblems,
+    /// Some calendars or events couldn't be synced.
+    Partial,
+    /// Nothing could be synced.
+    Failed,
+    /// Nothing could be synced, because signing in failed.
+    AuthFailed,
+}
+
+impl Status {
+    /// What cal-sync exits with.
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Ok => 0,
+            Status::Failed => 1,
+            Status::AuthFailed => 2,
+            Status::OrgProblems => 3,
+            Status::Partial => 4,
+        }
+    }
+}
+
+/// Everything that happened in a run.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub status: Status,
+    pub exit_code: u8,
+    /// How many org items had timestamps or clocks.
+    pub items: usize,
+    pub calendars: BTreeMap<String, CalendarReport>,
+    pub failures: Vec<FailureReport>,
+    /// Problems with org files, keyed by file.
+    pub warnings: BTreeMap<String, Vec<FileWarning>>,
+    /// Org files that couldn't be read at all.
+    pub skipped: Vec<SkippedFile>,
+    pub timings: Timings,
+    /// What stopped the run, if anything did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the error means for the status.
+    #[serde(skip)]
+    failed_with: Option<Status>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CalendarReport {
+    /// Whether the calendar could be synced at all.
+    pub synced: bool,
+    #[serde(flatten)]
+    pub counts: Counts,
+    /// How many events couldn't be synced.
+    pub failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailureReport {
+    pub calendar: String,
+    pub op: &'static str,
+    pub summary: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileWarning {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: String,
+}
+
+/// How long each part of the run took, in milliseconds.
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    pub parse_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_ms: Option<i64>,
+    pub total_ms: i64,
+}
+
+impl RunReport {
+    pub fn new(parsed: &Parsed) -> Self {
+        let mut by_file: BTreeMap<String, Vec<FileWarning>> = BTreeMap::new();
+        for w in &parsed.warnings {
+            by_file
+                .entry(w.path.to_string_lossy().into_owned())
+                .or_default()
+                .push(FileWarning {
+                    line: w.line,
+                    message: w.message.clone(),
+                });
+        }
+
+        let mut report = Self {
+            status: Status::Ok,
+            exit_code: 0,
+            items: parsed.items.len(),
+            calendars: BTreeMap::new(),
+            failures: vec![],
+            warnings: by_file,
+            skipped: parsed
+                .skipped
+                .iter()
+                .map(|s| SkippedFile {
+                    path: s.path.to_string_lossy().into_owned(),
+                    reason: s.reason.clone(),
+                })
+                .collect(),
+            timings: Timings::default(),
+            error: None,
+            failed_with: None,
+        };
+        report.update_status();
+        report
+    }
+
+    /// Records what happened to each calendar.
+    pub fn add_outcome(&mut self, outcome: &Outcome) {
+        for id in outcome.plans.keys() {
+            self.calendars.entry(id.clone()).or_default().synced = true;
+        }
+        for (id, counts) in &outcome.counts {
+            let cal = self.calendars.entry(id.clone()).or_default();
+            cal.synced = true;
+            cal.counts = *counts;
+        }
+        for id in &outcome.failed {
+            self.calendars.entry(id.clone()).or_default();
+        }
+        for (id, f) in &outcome.failures {
+            self.calendars.entry(id.clone()).or_default().failed += 1;
+            self.failures.push(FailureReport {
+                calendar: id.clone(),
+                op: f.op,
+                summary: f.summary.clone(),
+                error: format!("{:#}", f.error),
+            });
+        }
+        self.update_status();
+    }
+
+    /// Records the error that stopped the run.
+    pub fn fail(&mut self, e: &Report) {
+        self.error = Some(format!("{e:#}"));
+        self.failed_with = Some(if e.downcast_ref::<AuthFailed>().is_some() {
+            Status::AuthFailed
+        } else if e.downcast_ref::<OrgFailed>().is_some() {
+            Status::OrgProblems
+        } else {
+            Status::Failed
+        });
+        self.update_status();
+    }
+
+    /// Fills in how long things took.
+    pub fn time(&mut self, start: Timestamp, parsed: Timestamp, synced: Option<Timestamp>) {
+        let ms = |from: Timestamp, to: Timestamp| to.duration_since(from).as_millis() as i64;
+        let end = synced.unwrap_or(parsed);
+        self.timings = Timings {
+            parse_ms: ms(start, parsed),
+            sync_ms: synced.map(|s| ms(parsed, s)),
+            total_ms: ms(start, end),
+        };
+    }
+
+    fn update_status(&mut self) {
+        let synced = self.calendars.values().filter(|c| c.synced).count();
+        self.status = if let Some(status) = self.failed_with {
+            status
+        } else if !self.calendars.is_empty() && synced == 0 {
+            Status::Failed
+        } else if synced < self.calendars.len() || !self.failures.is_empty() {
+            Status::Partial
+        } else if !self.warnings.is_empty() || !self.skipped.is_empty() {
+            Status::OrgProblems
+        } else {
+            Status::Ok
+        };
+        self.exit_code = self.status.code();
+    }
+}
@@ -0,0 +1,216 @@
+//! Retrying requests that fail for reasons that might go away, like rate limits, and limiting
+//! how many requests we have in flight at once.
+
+use std::{future::Future, time::Duration};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::backend::{ApiError, CalendarBackend, RemoteEvent, SyncEvent};
+
+/// How to retry a failed request.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// How many times to try, including the first.
+    pub attempts: u32,
+    /// The backoff before the first retry, which doubles with each one after.
+    pub base: Duration,
+    /// The longest we'll back off for, unless the service asks for longer.
+    pub max: Duration,
+}
+
+impl Retry {
+    /// Runs `f` until it succeeds, fails with an error that isn't worth retrying, or we run out
+    /// of attempts.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let e = match f().await {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+
+            attempt += 1;
+            if attempt >= self.attempts || !is_retryable(&e) {
+                return Err(e);
+            }
+
+            let delay = e
+                .downcast_ref::<ApiError>()
+                .and_then(|e| e.retry_after)
+                .unwrap_or_else(|| self.backoff(attempt));
+            warn!("{what} failed, retrying in {delay:?}: {e}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Like [`run`](Self::run), for a batch of requests that each succeed or fail on their
+    /// own. `f` is given the items still left to do, and has to return a result for each.
+    pub async fn run_many<I, T, F, Fut>(&self, what: &str, items: &[I], mut f: F) -> Vec<Result<T>>
+    where
+        I: Clone,
+        F: FnMut(Vec<I>) -> Fut,
+        Fut: Future<Output = Vec<Result<T>>>,
+    {
+        let mut out: Vec<Option<Result<T>>> = items.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..items.len()).collect();
+        let mut attempt = 0;
+        while !pending.is_empty() {
+            let results = f(pending.iter().map(|&i| items[i].clone()).collect()).await;
+            attempt += 1;
+
+            let mut retry = vec![];
+            let mut delay = None;
+            for (i, res) in pending.into_iter().zip(results) {
+                match res {
+                    Err(e) if attempt < self.attempts && is_retryable(&e) => {
+                        let after = e.downcast_ref::<ApiError>().and_then(|e| e.retry_after);
+                        delay = delay.max(after);
+                        retry.push(i);
+                    }
+                    res => out[i] = Some(res),
+                }
+            }
+
+            if !retry.is_empty() {
+                let delay = delay.unwrap_or_else(|| self.backoff(attempt));
+                warn!(
+                    "{what}: {} requests failed, retrying in {delay:?}",
+                    retry.len()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            pending = retry;
+        }
+
+        out.into_iter()
+            .map(|res| res.unwrap_or_else(|| Err(eyre!("{what}: no result"))))
+            .collect()
+    }
+
+    /// Exponential backoff with full jitter, so that requests that failed together don't all
+    /// retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max);
+
+        cap.mul_f64(jitter())
+    }
+}
+
+/// Whether `e` might go away if we try again.
+fn is_retryable(e: &Report) -> bool {
+    if let Some(e) = e.downcast_ref::<ApiError>() {
+        return e.is_retryable();
+    }
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout() || e.is_connect();
+    }
+
+    false
+}
+
+/// A random number in `[0, 1)`, or 1 if the system has no randomness to give us, which only
+/// means backing off for longer.
+fn jitter() -> f64 {
+    let mut buf = [0; 8];
+    if getrandom::getrandom(&mut buf).is_err() {
+        return 1.0;
+    }
+
+    (u64::from_le_bytes(buf) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Wraps a backend so that at most `concurrency` requests are in flight at once, and failed
+/// requests are retried.
+pub struct Throttled<B> {
+    inner: B,
+    permits: Semaphore,
+    retry: Retry,
+}
+
+impl<B> Throttled<B> {
+    pub fn new(inner: B, concurrency: usize, retry: Retry) -> Self {
+        Self {
+            inner,
+            permits: Semaphore::new(concurrency),
+            retry,
+        }
+    }
+
+    /// Runs `f` once there's a permit free. Each attempt takes its own, so that requests
+    /// waiting to be retried don't hold up the rest.
+    async fn with_permit<T>(&self, f: impl Future<Output = T>) -> T {
+        // The semaphore is never closed, so there's always a permit eventually.
+        let _permit = self.permits.acquire().await;
+        f.await
+    }
+}
+
+impl<B: CalendarBackend> CalendarBackend for Throttled<B> {
+    async fn list(&self) -> Result<Vec<RemoteEvent>> {
+        self.retry
+            .run("list", || self.with_permit(self.inner.list()))
+            .await
+    }
+
+    async fn insert(&self, ev: &SyncEvent) -> Result<String> {
+        self.retry
+            .run(&format!("insert {}", ev.summary), || {
+                self.with_permit(self.inner.insert(ev))
+            })
+            .await
+    }
+
+    async fn update(&self, id: &str, ev: &SyncEvent) -> Result<()> {
+        self.retry
+            .run(&format!("update {}", ev.summary), || {
+                self.with_permit(self.inner.update(id, ev))
+            })
+            .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.retry
+            .run(&format!("delete {id}"), || {
+                self.with_permit(self.inner.delete(id))
+            })
+            .await
+    }
+
+    fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    // A batch is a single request, so it only takes up one permit.
+    async fn insert_batch(&self, evs: &[&SyncEvent]) -> Vec<Result<String>> {
+        self.retry
+            .run_many("insert", evs, |evs| async move {
+                self.with_permit(self.inner.insert_batch(&evs)).await
+            })
+            .await
+    }
+
+    async fn update_batch(&self, evs: &[(&str, &SyncEvent)]) -> Vec<Result<()>> {
+        self.retry
+            .run_many("update", evs, |evs| async move {
+                self.with_permit(self.inner.update_batch(&evs)).await
+            })
+            .await
+    }
+
+    async fn delete_batch(&self, ids: &[&str]) -> Vec<Result<()>> {
+        self.retry
+            .run_many("delete", ids, |ids| async move {
+                self.with_permit(self.inner.delete_batch(&ids)).await
+            })
+            .await
+    }
+}
@@ -0,0 +1,49 @@
+//! What we last wrote to the calendar, kept between runs.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    /// Keyed by the calendar's name in the config.
+    pub calendars: HashMap<String, CalendarState>,
+}
+
+/// What we last wrote to a single calendar.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CalendarState {
+    /// Keyed by [`SyncEvent::key`](crate::backend::SyncEvent::key).
+    pub events: HashMap<String, StateEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub remote_id: String,
+    /// [`SyncEvent::hash`](crate::backend::SyncEvent::hash) of what we wrote. Missing in state
+    /// files from before we recorded it.
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// [`Dateish::canonical`](crate::org::Dateish::canonical) of the start and end we synced.
+    pub start: String,
+    pub end: Option<String>,
+}
+
+impl SyncState {
+    /// Loads the state at `path`, or starts from scratch if there isn't one yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).wrap_err_with(|| format!("Corrupt sync state {path:?}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        atomic::write(path, serde_json::to_string_pretty(self)?.as_bytes(), 0o600)
+    }
+}
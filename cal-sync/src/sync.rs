@@ -0,0 +1,635 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use futures::future::join_all;
+use jiff::{Unit, Zoned};
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backend::{CalendarBackend, Origin, RemoteEvent, SyncEvent},
+    config::{ClockConfig, Config, Target},
+    org::{AgendaItem, Dateish, Reminder, ReminderMethod, RepeatedDate, TimestampKind},
+    state::{CalendarState, StateEntry, SyncState},
+    writeback::{self, Edit},
+};
+
+/// Turns every timestamp of every item into the event we want in the calendar, grouped by
+/// which of the configured calendars they go to. Every calendar is included, even if nothing
+/// goes there, so that its old events get cleaned up.
+pub fn sync_events(
+    items: Vec<AgendaItem>,
+    config: &Config,
+    root: &Path,
+) -> BTreeMap<String, Vec<SyncEvent>> {
+    // Running clocks are shown as ending on the minute we sync.
+    let now = Zoned::now()
+        .round(Unit::Minute)
+        .unwrap_or_else(|_| Zoned::now());
+    let mut calendars: BTreeMap<String, Vec<SyncEvent>> = config
+        .calendars
+        .keys()
+        .map(|id| (id.clone(), vec![]))
+        .collect();
+
+    for mut item in items {
+        // Time clocked on items that are filtered out still goes in the timesheet. Items
+        // imported from a calendar are already there.
+        if !config.filter.keeps(&item) || item.properties.contains_key("GCAL_ID") {
+            item.timestamps.clear();
+        }
+        let target = config.route(&item, root);
+        let events = item.timestamps.iter().map(|ts| SyncEvent {
+            key: item.key(ts),
+            summary: target.title.clone(),
+            description: description(&item),
+            location: item.location.clone(),
+            start: ts.start.clone(),
+            end: calendar_end(&ts.start, ts.end.as_ref()),
+            recurrence: ts.repeat.as_ref().map(|r| r.rrule(&ts.start)),
+            color: target.color.clone(),
+            reminders: reminders(&item, ts, &target),
+            origin: Origin {
+                path: item.path.clone(),
+                range: ts.range,
+                raw: ts.raw.clone(),
+                has_end: ts.has_end,
+                clocked: false,
+                line: ts.line,
+            },
+        });
+        calendars.entry(target.calendar).or_default().extend(events);
+
+        if let Some(clock) = &config.clock {
+            calendars
+                .entry(clock.calendar.clone())
+                .or_default()
+                .extend(clock_events(&item, clock, &now));
+        }
+    }
+
+    calendars
+}
+
+/// Turns the time clocked on `item` into events. Running clocks end `now`.
+fn clock_events<'a>(
+    item: &'a AgendaItem,
+    clock: &'a ClockConfig,
+    now: &'a Zoned,
+) -> impl Iterator<Item = SyncEvent> + 'a {
+    item.clocks.iter().filter_map(move |c| {
+        let end = match &c.end {
+            Some(end) => end.clone(),
+            None if clock.running => now.clone(),
+            None => return None,
+        };
+
+        Some(SyncEvent {
+            key: item.clock_key(c),
+            summary: item.name.clone(),
+            description: description(item),
+            location: None,
+            start: Dateish::Precise(c.start.clone()),
+            end: Some(Dateish::Precise(end)),
+            recurrence: None,
+            color: clock.color(&item.category),
+            reminders: None,
+            origin: Origin {
+                path: item.path.clone(),
+                range: c.range,
+                raw: c.raw.clone(),
+                has_end: true,
+                clocked: true,
+                line: c.line,
+            },
+        })
+    })
+}
+
+/// Everything a sync would change, worked out without changing anything.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub inserts: Vec<SyncEvent>,
+    /// The remote event as it is now, and what it'll be replaced with.
+    pub updates: Vec<(RemoteEvent, SyncEvent)>,
+    pub deletes: Vec<RemoteEvent>,
+    /// Remote ids of events that are already up to date.
+    pub unchanged: Vec<(String, SyncEvent)>,
+    /// Timestamps to move in the org files, for two-way sync.
+    pub edits: Vec<Edit>,
+    /// Events that changed on both sides, which we leave alone.
+    pub conflicts: Vec<SyncEvent>,
+}
+
+/// Brings the calendar behind `backend` in line with `events`, only touching events that
+/// actually changed.
+///
+/// If `state` is given, it's updated to reflect what's in the calendar afterwards. With
+/// `two_way`, events that were moved in the calendar since the last sync get moved in the org
+/// files too, instead of being moved back.
+///
+/// Events that fail to sync don't stop the others; they're returned instead.
+pub async fn sync(
+    backend: &impl CalendarBackend,
+    events: Vec<SyncEvent>,
+    state: Option<&mut CalendarState>,
+    two_way: bool,
+) -> Result<Applied> {
+    let plan = plan(backend, events, state.as_deref(), two_way).await?;
+    apply(backend, plan, state).await
+}
+
+/// A request that failed, even after retrying.
+#[derive(Debug)]
+pub struct Failure {
+    /// `insert`, `update` or `delete`.
+    pub op: &'static str,
+    pub summary: String,
+    pub error: Report,
+}
+
+/// How many events a sync changed in one calendar.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub inserted: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+    pub conflicts: usize,
+}
+
+/// What [`apply`] did to one calendar.
+#[derive(Debug, Default)]
+pub struct Applied {
+    pub counts: Counts,
+    /// Events that failed, even after retrying.
+    pub failures: Vec<Failure>,
+    /// Events that changed on both sides, and were left alone.
+    pub conflicts: Vec<SyncEvent>,
+}
+
+/// What happened when syncing several calendars.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// With `plan_only`, what would've happened to each calendar.
+    pub plans: BTreeMap<String, Plan>,
+    /// What was changed in each calendar that could be synced.
+    pub counts: BTreeMap<String, Counts>,
+    /// Calendars that couldn't be synced at all.
+    pub failed: Vec<String>,
+    /// Events that couldn't be synced, with the calendar they're in.
+    pub failures: Vec<(String, Failure)>,
+    /// Events that changed on both sides, with the calendar they're in.
+    pub conflicts: Vec<(String, SyncEvent)>,
+}
+
+impl Outcome {
+    /// Prints what changed in each calendar and every failed event, and returns an error if
+    /// anything failed.
+    pub fn report(&self) -> Result<()> {
+        for (_, ev) in &self.conflicts {
+            println!(
+                "! conflict: {} ({})",
+                ev.summary,
+                ev.origin.path.to_string_lossy()
+            );
+        }
+        for (cal, c) in &self.counts {
+            let failed = self.failures.iter().filter(|(id, _)| id == cal).count();
+            if failed == 0 {
+                println!("-{} +{} ~{}", c.deleted, c.inserted, c.updated);
+            } else {
+                println!("-{} +{} ~{} ✗{failed}", c.deleted, c.inserted, c.updated);
+            }
+        }
+        for (cal, f) in &self.failures {
+            println!("✗ {cal}: couldn't {} {}: {:#}", f.op, f.summary, f.error);
+        }
+
+        self.result()
+    }
+
+    /// An error if anything failed, without printing anything.
+    pub fn result(&self) -> Result<()> {
+        match (self.failed.is_empty(), self.failures.len()) {
+            (true, 0) => Ok(()),
+            (true, n) => Err(eyre!("Couldn't sync {n} events")),
+            (false, n) => Err(eyre!(
+                "Couldn't sync {} ({n} other events failed)",
+                self.failed.join(", ")
+            )),
+        }
+    }
+}
+
+/// Syncs each calendar in `calendars` through its backend in `backends`, or with `plan_only`,
+/// only works out what that would do.
+///
+/// Calendars are reconciled independently, so one that's unreachable doesn't hold up the
+/// others.
+pub async fn sync_calendars<B: CalendarBackend>(
+    backends: &BTreeMap<String, Result<B>>,
+    calendars: BTreeMap<String, Vec<SyncEvent>>,
+    mut state: Option<&mut SyncState>,
+    two_way: bool,
+    plan_only: bool,
+) -> Outcome {
+    let mut outcome = Outcome::default();
+    for (id, events) in calendars {
+        let cal_state = state
+            .as_deref_mut()
+            .map(|s| s.calendars.entry(id.clone()).or_default());
+
+        let res = match backends.get(&id) {
+            Some(Ok(backend)) if plan_only => {
+                match plan(backend, events, cal_state.as_deref(), two_way).await {
+                    Ok(plan) => {
+                        outcome.plans.insert(id.clone(), plan);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Some(Ok(backend)) => match sync(backend, events, cal_state, two_way).await {
+                Ok(applied) => {
+                    outcome.counts.insert(id.clone(), applied.counts);
+                    outcome
+                        .failures
+                        .extend(applied.failures.into_iter().map(|f| (id.clone(), f)));
+                    outcome
+                        .conflicts
+                        .extend(applied.conflicts.into_iter().map(|ev| (id.clone(), ev)));
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Some(Err(e)) => Err(eyre!("{e:#}")),
+            None => Err(eyre!("No backend for calendar {id}")),
+        };
+
+        if let Err(e) = res {
+            error!("Failed to sync {id}: {e:?}");
+            outcome.failed.push(id);
+        }
+    }
+
+    outcome
+}
+
+/// Works out what [`sync`] would do, only reading from the calendar.
+pub async fn plan(
+    backend: &impl CalendarBackend,
+    mut events: Vec<SyncEvent>,
+    state: Option<&CalendarState>,
+    two_way: bool,
+) -> Result<Plan> {
+    let mut existing: HashMap<String, RemoteEvent> = HashMap::new();
+    let mut stale = vec![];
+    for ev in backend.list().await? {
+        // Events from before we keyed them, and duplicates of a key, can't be matched up
+        // with anything, so they get deleted.
+        match ev.key.clone() {
+            Some(key) if !existing.contains_key(&key) => {
+                existing.insert(key, ev);
+            }
+            _ => stale.push(ev),
+        }
+    }
+
+    let mut edits = vec![];
+    let mut conflicts = vec![];
+    if let (true, Some(state)) = (two_way, state) {
+        events.retain_mut(|ev| {
+            let (Some(remote), Some(last)) = (existing.get(&ev.key), state.events.get(&ev.key))
+            else {
+                return true;
+            };
+            let Some(remote_start) = &remote.start else {
+                return true;
+            };
+            if ev.origin.clocked {
+                return true;
+            }
+
+            let remote_changed = !same_times(remote_start, remote.end.as_ref(), last);
+            let local_changed = !same_times(&ev.start, ev.end.as_ref(), last);
+            let agree = remote_start.canonical() == ev.start.canonical()
+                && remote.end.as_ref().map(Dateish::canonical)
+                    == ev.end.as_ref().map(Dateish::canonical);
+
+            match (remote_changed, local_changed) {
+                (true, false) => {
+                    let tz = match &ev.start {
+                        Dateish::Precise(z) => z.time_zone().clone(),
+                        Dateish::AllDay(_) => jiff::tz::TimeZone::system(),
+                    };
+                    // Don't add an end to the timestamp if the duration is still our default.
+                    let end = if ev.origin.has_end
+                        || duration(remote_start, remote.end.as_ref())
+                            != duration(&ev.start, ev.end.as_ref())
+                    {
+                        org_end(remote_start, remote.end.as_ref())
+                    } else {
+                        None
+                    };
+
+                    edits.push(Edit {
+                        key: ev.key.clone(),
+                        origin: ev.origin.clone(),
+                        start: remote_start.clone(),
+                        end,
+                        tz,
+                    });
+                    ev.start = remote_start.clone();
+                    ev.end = remote.end.clone();
+                    true
+                }
+                (true, true) if !agree => {
+                    warn!("{} changed in both org and the calendar", ev.summary);
+                    conflicts.push(ev.clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    for ev in &conflicts {
+        existing.remove(&ev.key);
+    }
+
+    // Three-way diff: anything wanted that doesn't exist gets inserted, anything whose hash
+    // changed gets updated, and anything left over gets deleted.
+    let mut plan = Plan {
+        edits,
+        conflicts,
+        ..Default::default()
+    };
+    for ev in events {
+        match existing.remove(&ev.key) {
+            None => plan.inserts.push(ev),
+            Some(old) => {
+                // Events written before we hashed them remotely fall back to what we recorded.
+                let old_hash = old.hash.clone().or_else(|| {
+                    state
+                        .and_then(|s| s.events.get(&ev.key))
+                        .filter(|e| e.remote_id == old.id)
+                        .and_then(|e| e.hash.clone())
+                });
+
+                if old_hash.as_deref() == Some(&ev.hash()) {
+                    plan.unchanged.push((old.id, ev));
+                } else {
+                    plan.updates.push((old, ev));
+                }
+            }
+        }
+    }
+    stale.extend(existing.into_values());
+    plan.deletes = stale;
+
+    Ok(plan)
+}
+
+/// Carries out `plan`, which should have come from [`plan`] with the same `backend`, returning
+/// what changed and the events that failed.
+pub async fn apply(
+    backend: &impl CalendarBackend,
+    plan: Plan,
+    state: Option<&mut CalendarState>,
+) -> Result<Applied> {
+    let Plan {
+        inserts,
+        updates,
+        deletes,
+        unchanged,
+        edits,
+        conflicts,
+    } = plan;
+
+    let mut unwritten = HashSet::new();
+    if !edits.is_empty() {
+        info!("Writing back: {}", edits.len());
+        unwritten.extend(writeback::apply(edits).into_iter().map(|e| e.key));
+    }
+
+    // What the calendar looks like after this run. Conflicted events keep their old state, so
+    // they're still detected as conflicts next time.
+    let mut new_state: HashMap<String, StateEntry> = HashMap::new();
+    if let Some(state) = state.as_deref() {
+        for ev in &conflicts {
+            if let Some(entry) = state.events.get(&ev.key) {
+                new_state.insert(ev.key.clone(), entry.clone());
+            }
+        }
+    }
+
+    // Requests are grouped into batches as big as the backend allows, and the batches sent
+    // all at once.
+    let size = backend.batch_size().max(1);
+
+    let dels = join_all(deletes.chunks(size).map(|chunk| async move {
+        let ids: Vec<&str> = chunk
+            .iter()
+            .inspect(|ev| debug!("del {}", ev.summary))
+            .map(|ev| ev.id.as_str())
+            .collect();
+        backend.delete_batch(&ids).await
+    }))
+    .await
+    .into_iter()
+    .flatten();
+
+    // Await all delete tasks
+    let mut failures = vec![];
+    let mut deleted_evs = 0;
+    for (res, ev) in dels.zip(&deletes) {
+        match res {
+            Ok(()) => deleted_evs += 1,
+            Err(error) => failures.push(Failure {
+                op: "delete",
+                summary: ev.summary.clone(),
+                error,
+            }),
+        }
+    }
+    info!("Deleted: {deleted_evs}");
+
+    let upds = join_all(updates.chunks(size).map(|chunk| async move {
+        let evs: Vec<(&str, &SyncEvent)> = chunk
+            .iter()
+            .inspect(|(_, ev)| debug!("upd {}", ev.summary))
+            .map(|(old, ev)| (old.id.as_str(), ev))
+            .collect();
+        backend.update_batch(&evs).await
+    }))
+    .await
+    .into_iter()
+    .flatten();
+
+    // Await all update tasks
+    let mut updated_evs = 0;
+    for (res, (old, ev)) in upds.zip(&updates) {
+        match res {
+            Ok(()) => {
+                new_state.insert(ev.key.clone(), state_entry(old.id.clone(), ev));
+                updated_evs += 1;
+            }
+            Err(error) => {
+                // The event is still what we last synced, as far as two-way sync is concerned.
+                if let Some(entry) = state.as_deref().and_then(|s| s.events.get(&ev.key)) {
+                    new_state.insert(ev.key.clone(), entry.clone());
+                }
+                failures.push(Failure {
+                    op: "update",
+                    summary: ev.summary.clone(),
+                    error,
+                });
+            }
+        }
+    }
+    info!("Updated: {updated_evs}");
+
+    // Now, let's add all of our new org tasks
+    let adds = join_all(inserts.chunks(size).map(|chunk| async move {
+        let evs: Vec<&SyncEvent> = chunk
+            .iter()
+            .inspect(|ev| debug!("ins {}", ev.summary))
+            .collect();
+        backend.insert_batch(&evs).await
+    }))
+    .await
+    .into_iter()
+    .flatten();
+
+    // Await all insert tasks
+    let mut inserted_evs = 0;
+    for (res, ev) in adds.zip(&inserts) {
+        match res {
+            Ok(id) => {
+                new_state.insert(ev.key.clone(), state_entry(id, ev));
+                inserted_evs += 1;
+            }
+            Err(error) => failures.push(Failure {
+                op: "insert",
+                summary: ev.summary.clone(),
+                error,
+            }),
+        }
+    }
+    info!("Inserted: {inserted_evs}");
+    info!("Unchanged: {}", unchanged.len());
+
+    let counts = Counts {
+        inserted: inserted_evs,
+        updated: updated_evs,
+        deleted: deleted_evs,
+        unchanged: unchanged.len(),
+        conflicts: conflicts.len(),
+    };
+    for (id, ev) in unchanged {
+        new_state.insert(ev.key.clone(), state_entry(id, &ev));
+    }
+    // Moves that couldn't be written back keep their old state too, so that they're written back
+    // next time rather than undone.
+    if let Some(old) = state.as_deref() {
+        for key in &unwritten {
+            if let Some(entry) = old.events.get(key) {
+                new_state.insert(key.clone(), entry.clone());
+            }
+        }
+    }
+    if let Some(state) = state {
+        state.events = new_state;
+    }
+
+    Ok(Applied {
+        counts,
+        failures,
+        conflicts,
+    })
+}
+
+/// The item's own reminders, or else the calendar's plus a deadline's warning period.
+fn reminders(item: &AgendaItem, ts: &RepeatedDate, target: &Target) -> Option<Vec<Reminder>> {
+    if let Some(own) = &item.reminders {
+        return Some(own.clone());
+    }
+
+    match ts.warning {
+        Some(warning) if ts.kind == TimestampKind::Deadline => {
+            let mut reminders = target.reminders.clone().unwrap_or_default();
+            reminders.push(Reminder {
+                method: ReminderMethod::Popup,
+                minutes: warning / 60,
+            });
+            Some(reminders)
+        }
+        _ => target.reminders.clone(),
+    }
+}
+
+/// The headline's text, followed by a link back to it.
+fn description(item: &AgendaItem) -> String {
+    if item.body.is_empty() {
+        item.link()
+    } else {
+        format!("{}\n\n{}", item.body, item.link())
+    }
+}
+
+fn state_entry(remote_id: String, ev: &SyncEvent) -> StateEntry {
+    StateEntry {
+        remote_id,
+        hash: Some(ev.hash()),
+        start: ev.start.canonical(),
+        end: ev.end.as_ref().map(Dateish::canonical),
+    }
+}
+
+/// Whether `start` and `end` are what we last synced.
+fn same_times(start: &Dateish, end: Option<&Dateish>, last: &StateEntry) -> bool {
+    start.canonical() == last.start && end.map(Dateish::canonical) == last.end
+}
+
+/// The end of an event as calendars expect it. All-day events end at the start of the day
+/// after their last day, whereas org ranges include their last day.
+fn calendar_end(start: &Dateish, end: Option<&Dateish>) -> Option<Dateish> {
+    let Dateish::AllDay(s) = start else {
+        return end.cloned();
+    };
+
+    let last = match end {
+        Some(Dateish::AllDay(e)) => *e,
+        Some(Dateish::Precise(e)) => e.date(),
+        None => *s,
+    };
+
+    last.tomorrow().ok().map(Dateish::AllDay)
+}
+
+/// Reverses [`calendar_end`], returning `None` for single-day events.
+fn org_end(start: &Dateish, end: Option<&Dateish>) -> Option<Dateish> {
+    match (start, end) {
+        (Dateish::AllDay(s), Some(Dateish::AllDay(e))) => {
+            let last = e.yesterday().ok()?;
+            (last > *s).then_some(Dateish::AllDay(last))
+        }
+        _ => end.cloned(),
+    }
+}
+
+/// The length of an event in seconds.
+fn duration(start: &Dateish, end: Option<&Dateish>) -> Option<i64> {
+    match (start, end) {
+        (Dateish::Precise(s), Some(Dateish::Precise(e))) => {
+            Some(e.timestamp().as_second() - s.timestamp().as_second())
+        }
+        (Dateish::AllDay(s), Some(Dateish::AllDay(e))) => {
+            Some(i64::from((*e - *s).get_days()) * 86400)
+        }
+        _ => None,
+    }
+}
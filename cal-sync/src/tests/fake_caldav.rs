@@ -0,0 +1,173 @@
+//! An in-process stand-in for a CalDAV server with a single calendar collection, so the CalDAV
+//! backend can be tested without one.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+/// Where the calendar collection lives.
+const COLLECTION: &str = "/calendars/personal/";
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Resource path to iCalendar data.
+    resources: BTreeMap<String, String>,
+    /// Every PUT and DELETE we've served, like `PUT /calendars/personal/x.ics`, with whether it
+    /// had `If-None-Match: *`.
+    writes: Vec<(String, bool)>,
+    /// Statuses and bodies to fail the next requests with.
+    fail_next: VecDeque<(u16, String)>,
+}
+
+#[derive(Clone)]
+pub struct FakeCalDav {
+    inner: Arc<Mutex<Inner>>,
+    /// The calendar collection's URL.
+    pub url: String,
+}
+
+impl FakeCalDav {
+    /// Starts serving an empty calendar on an ephemeral port.
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let fake = Self {
+            inner: Arc::default(),
+            url: format!("http://{addr}{COLLECTION}"),
+        };
+
+        // REPORT isn't a method axum routes on, so everything goes through one handler.
+        let app = Router::new().fallback(handle).with_state(fake.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        fake
+    }
+
+    /// Every resource in the calendar, keyed by path.
+    pub fn resources(&self) -> BTreeMap<String, String> {
+        self.inner.lock().unwrap().resources.clone()
+    }
+
+    /// Adds a resource called `name` to the calendar, as another client would, returning its
+    /// path.
+    pub fn add_resource(&self, name: &str, data: &str) -> String {
+        let path = format!("{COLLECTION}{name}");
+        self.inner
+            .lock()
+            .unwrap()
+            .resources
+            .insert(path.clone(), data.to_string());
+
+        path
+    }
+
+    pub fn writes(&self) -> Vec<(String, bool)> {
+        self.inner.lock().unwrap().writes.clone()
+    }
+
+    /// Makes the next request fail with `status`, explained by `body`.
+    pub fn fail_next(&self, status: u16, body: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .fail_next
+            .push_back((status, body.to_string()));
+    }
+}
+
+async fn handle(
+    State(fake): State<FakeCalDav>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let mut inner = fake.inner.lock().unwrap();
+    if let Some((status, body)) = inner.fail_next.pop_front() {
+        return (StatusCode::from_u16(status).unwrap(), body).into_response();
+    }
+
+    let path = uri.path().to_string();
+    let in_collection = path.starts_with(COLLECTION) && path.len() > COLLECTION.len();
+    match method.as_str() {
+        "REPORT" if path == COLLECTION => {
+            let depth = headers.get("depth").and_then(|v| v.to_str().ok());
+            if depth != Some("1") || !body.contains("calendar-query") {
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+
+            (
+                StatusCode::MULTI_STATUS,
+                [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+                multistatus(&inner.resources),
+            )
+                .into_response()
+        }
+        "PUT" if in_collection => {
+            let create = headers.get(header::IF_NONE_MATCH).is_some_and(|v| v == "*");
+            inner.writes.push((format!("PUT {path}"), create));
+            if create && inner.resources.contains_key(&path) {
+                return StatusCode::PRECONDITION_FAILED.into_response();
+            }
+
+            match inner.resources.insert(path, body) {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::CREATED,
+            }
+            .into_response()
+        }
+        "DELETE" if in_collection => {
+            inner.writes.push((format!("DELETE {path}"), false));
+
+            match inner.resources.remove(&path) {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::NOT_FOUND,
+            }
+            .into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+/// Answers a calendar-query with every resource in the calendar. Servers differ in whether they
+/// escape the data or wrap it in CDATA, so every other resource gets the other.
+fn multistatus(resources: &BTreeMap<String, String>) -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:cal="urn:ietf:params:xml:ns:caldav">"#,
+    );
+    for (i, (path, data)) in resources.iter().enumerate() {
+        let data = if i % 2 == 0 {
+            data.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+        } else {
+            format!("<![CDATA[{data}]]>")
+        };
+        xml += &format!(
+            "
+  <d:response>
+    <d:href>{path}</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:getetag>\"{i}\"</d:getetag>
+        <cal:calendar-data>{data}</cal:calendar-data>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>"
+        );
+    }
+    xml += "\n</d:multistatus>\n";
+
+    xml
+}
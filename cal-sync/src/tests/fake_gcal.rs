@@ -0,0 +1,522 @@
+//! An in-process stand-in for the parts of the Google Calendar v3 API we use, so syncing can be
+//! tested without a Google account.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use google_calendar::{types::Event, Client};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::gcal::{Endpoints, Session};
+
+pub const CLIENT_ID: &str = "fake-client";
+pub const CLIENT_SECRET: &str = "fake-secret";
+pub const REFRESH_TOKEN: &str = "fake-refresh";
+/// The account in `tests/fixtures/auth/service-account.json`.
+pub const SERVICE_ACCOUNT: &str = "cal-sync@cal-sync-tests.iam.gserviceaccount.com";
+const SERVICE_ACCOUNT_PUBLIC_KEY: &str =
+    include_str!("../../tests/fixtures/auth/service-account.pub.pem");
+
+/// Small enough that every test pages through lists.
+const PAGE_SIZE: usize = 2;
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Calendar id to summary.
+    calendars: BTreeMap<String, String>,
+    /// Calendar id to event id to event.
+    events: BTreeMap<String, BTreeMap<String, Value>>,
+    next_id: u64,
+    /// How many inserts, patches and deletes we've served.
+    writes: usize,
+    /// How many batch requests we've served.
+    batches: usize,
+    /// How many access tokens we've handed out for refresh tokens.
+    refreshes: usize,
+    /// Authorization codes we've handed out, with the PKCE challenge and redirect they're for.
+    codes: HashMap<String, (String, String)>,
+    /// Statuses to fail the next writes with.
+    fail_next: VecDeque<u16>,
+}
+
+#[derive(Clone)]
+pub struct FakeGcal {
+    inner: Arc<Mutex<Inner>>,
+    pub endpoints: Endpoints,
+}
+
+impl FakeGcal {
+    /// Starts serving on an ephemeral port, with an empty calendar for every summary given.
+    pub async fn start(calendars: &[&str]) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut inner = Inner::default();
+        for (i, summary) in calendars.iter().enumerate() {
+            let id = format!("cal{i}");
+            inner.calendars.insert(id.clone(), summary.to_string());
+            inner.events.insert(id, BTreeMap::new());
+        }
+
+        let fake = Self {
+            inner: Arc::new(Mutex::new(inner)),
+            endpoints: Endpoints {
+                api: format!("http://{addr}/calendar/v3"),
+                auth: format!("http://{addr}/auth"),
+                token: format!("http://{addr}/token"),
+                batch: format!("http://{addr}/batch/calendar/v3"),
+            },
+        };
+
+        let app = Router::new()
+            .route("/calendar/v3/users/me/calendarList", get(calendar_list))
+            .route(
+                "/calendar/v3/calendars/{cal}/events",
+                get(list_events).post(insert_event),
+            )
+            .route(
+                "/calendar/v3/calendars/{cal}/events/{id}",
+                patch(patch_event).delete(delete_event),
+            )
+            .route("/batch/calendar/v3", post(batch))
+            .route("/token", post(token))
+            .with_state(fake.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        fake
+    }
+
+    /// A session that talks to us.
+    pub fn session(&self) -> Session {
+        let c = Client::new(
+            CLIENT_ID,
+            CLIENT_SECRET,
+            "http://localhost",
+            "fake-access",
+            REFRESH_TOKEN,
+        );
+        Session::new(c, "fake-access".to_string(), self.endpoints.clone())
+    }
+
+    /// Every event in the calendar called `summary`.
+    pub fn events(&self, summary: &str) -> Vec<Event> {
+        let inner = self.inner.lock().unwrap();
+        let (id, _) = inner
+            .calendars
+            .iter()
+            .find(|(_, s)| *s == summary)
+            .expect("No such calendar");
+
+        inner.events[id]
+            .values()
+            .map(|ev| serde_json::from_value(ev.clone()).unwrap())
+            .collect()
+    }
+
+    /// Adds an event to the calendar called `summary`, as someone else would, returning its id.
+    pub fn add_event(&self, summary: &str, ev: Value) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        let cal = inner
+            .calendars
+            .iter()
+            .find(|(_, s)| *s == summary)
+            .map(|(id, _)| id.clone())
+            .expect("No such calendar");
+
+        let reply = inner.insert(&cal, ev);
+        reply.body.unwrap()["id"].as_str().unwrap().to_string()
+    }
+
+    /// Changes the event called `event` in the calendar called `calendar`, as someone else would.
+    pub fn edit_event(&self, calendar: &str, event: &str, edit: impl FnOnce(&mut Value)) {
+        let mut inner = self.inner.lock().unwrap();
+        let cal = inner
+            .calendars
+            .iter()
+            .find(|(_, s)| *s == calendar)
+            .map(|(id, _)| id.clone())
+            .expect("No such calendar");
+
+        let ev = inner
+            .events
+            .get_mut(&cal)
+            .unwrap()
+            .values_mut()
+            .find(|ev| ev["summary"] == event)
+            .expect("No such event");
+        edit(ev);
+    }
+
+    /// Makes the next writes fail with `statuses`, one each, before any of them go through.
+    pub fn fail_next(&self, statuses: &[u16]) {
+        self.inner
+            .lock()
+            .unwrap()
+            .fail_next
+            .extend(statuses.iter().copied());
+    }
+
+    pub fn writes(&self) -> usize {
+        self.inner.lock().unwrap().writes
+    }
+
+    pub fn batches(&self) -> usize {
+        self.inner.lock().unwrap().batches
+    }
+
+    /// Does what the consent page does once the user agrees, returning where the browser is
+    /// redirected to.
+    pub fn consent(&self, url: &str) -> String {
+        let url = reqwest::Url::parse(url).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let code = format!("code{}", inner.next_id);
+        inner.codes.insert(
+            code.clone(),
+            (
+                query["code_challenge"].clone(),
+                query["redirect_uri"].clone(),
+            ),
+        );
+
+        let mut redirect = reqwest::Url::parse(&query["redirect_uri"]).unwrap();
+        redirect
+            .query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &query["state"]);
+        redirect.into()
+    }
+
+    pub fn refreshes(&self) -> usize {
+        self.inner.lock().unwrap().refreshes
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Page {
+    page_token: Option<String>,
+}
+
+/// Wraps one page of `items` in a list response. Page tokens are just offsets.
+fn page(items: Vec<Value>, page: Page) -> Json<Value> {
+    let start = page
+        .page_token
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(0)
+        .min(items.len());
+    let end = (start + PAGE_SIZE).min(items.len());
+
+    let mut body = json!({ "items": items[start..end] });
+    if end < items.len() {
+        body["nextPageToken"] = end.to_string().into();
+    }
+
+    Json(body)
+}
+
+async fn calendar_list(State(fake): State<FakeGcal>, Query(p): Query<Page>) -> Json<Value> {
+    let inner = fake.inner.lock().unwrap();
+    let items = inner
+        .calendars
+        .iter()
+        .map(|(id, summary)| json!({ "id": id, "summary": summary, "accessRole": "owner" }))
+        .collect();
+
+    page(items, p)
+}
+
+async fn list_events(
+    State(fake): State<FakeGcal>,
+    Path(cal): Path<String>,
+    Query(p): Query<Page>,
+) -> Result<Json<Value>, StatusCode> {
+    let inner = fake.inner.lock().unwrap();
+    let events = inner.events.get(&cal).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(page(events.values().cloned().collect(), p))
+}
+
+/// A response to a single request, whether it came on its own or in a batch.
+struct Reply {
+    status: StatusCode,
+    headers: Vec<(&'static str, String)>,
+    body: Option<Value>,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: vec![],
+            body: Some(body),
+        }
+    }
+
+    fn status(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    /// The next failure queued up with [`FakeGcal::fail_next`], as Google would send it.
+    fn injected(inner: &mut Inner) -> Option<Self> {
+        let status = StatusCode::from_u16(inner.fail_next.pop_front()?).unwrap();
+        let body = json!({
+            "error": {
+                "code": status.as_u16(),
+                "message": "Rate Limit Exceeded",
+                "errors": [{ "reason": "rateLimitExceeded" }],
+            }
+        });
+
+        Some(Self {
+            status,
+            headers: vec![("retry-after", "0".to_string())],
+            body: Some(body),
+        })
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        let mut resp = match self.body {
+            Some(body) => (self.status, Json(body)).into_response(),
+            None => self.status.into_response(),
+        };
+        for (k, v) in self.headers {
+            resp.headers_mut().insert(k, v.parse().unwrap());
+        }
+
+        resp
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, cal: &str, mut ev: Value) -> Reply {
+        if let Some(reply) = Reply::injected(self) {
+            return reply;
+        }
+        let Some(events) = self.events.get_mut(cal) else {
+            return Reply::status(StatusCode::NOT_FOUND);
+        };
+
+        self.next_id += 1;
+        let id = format!("ev{}", self.next_id);
+        ev["id"] = id.clone().into();
+        events.insert(id, ev.clone());
+        self.writes += 1;
+
+        Reply::ok(ev)
+    }
+
+    fn patch(&mut self, cal: &str, id: &str, changes: Value) -> Reply {
+        if let Some(reply) = Reply::injected(self) {
+            return reply;
+        }
+        let Some(ev) = self.events.get_mut(cal).and_then(|evs| evs.get_mut(id)) else {
+            return Reply::status(StatusCode::NOT_FOUND);
+        };
+
+        // Like the real thing, fields that aren't given are left alone.
+        for (k, v) in changes.as_object().into_iter().flatten() {
+            ev[k] = v.clone();
+        }
+        let ev = ev.clone();
+        self.writes += 1;
+
+        Reply::ok(ev)
+    }
+
+    fn delete(&mut self, cal: &str, id: &str) -> Reply {
+        if let Some(reply) = Reply::injected(self) {
+            return reply;
+        }
+
+        match self.events.get_mut(cal).and_then(|evs| evs.remove(id)) {
+            Some(_) => {
+                self.writes += 1;
+                Reply::status(StatusCode::NO_CONTENT)
+            }
+            None => Reply::status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+async fn insert_event(
+    State(fake): State<FakeGcal>,
+    Path(cal): Path<String>,
+    Json(ev): Json<Value>,
+) -> Reply {
+    fake.inner.lock().unwrap().insert(&cal, ev)
+}
+
+async fn patch_event(
+    State(fake): State<FakeGcal>,
+    Path((cal, id)): Path<(String, String)>,
+    Json(changes): Json<Value>,
+) -> Reply {
+    fake.inner.lock().unwrap().patch(&cal, &id, changes)
+}
+
+async fn delete_event(
+    State(fake): State<FakeGcal>,
+    Path((cal, id)): Path<(String, String)>,
+) -> Reply {
+    fake.inner.lock().unwrap().delete(&cal, &id)
+}
+
+/// Runs every request in a `multipart/mixed` batch, answering each in a part of our own.
+async fn batch(State(fake): State<FakeGcal>, headers: HeaderMap, body: String) -> Response {
+    let Some(boundary) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once("boundary="))
+        .map(|(_, b)| b.to_string())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let mut inner = fake.inner.lock().unwrap();
+    inner.batches += 1;
+
+    let mut out = String::new();
+    for part in body.split(&format!("--{boundary}")) {
+        let Some((part_head, request)) = part.trim_start().split_once("\r\n\r\n") else {
+            continue;
+        };
+        let Some(content_id) = part_head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-ID: "))
+            .map(|id| id.trim_matches(['<', '>']))
+        else {
+            continue;
+        };
+        let (request_head, request_body) = request.split_once("\r\n\r\n").unwrap_or((request, ""));
+        let request_line = request_head.lines().next().unwrap_or_default();
+        let (method, path) = request_line.split_once(' ').unwrap_or_default();
+        let body = serde_json::from_str(request_body.trim()).unwrap_or(Value::Null);
+
+        let segments: Vec<_> = path
+            .trim_start_matches("/calendar/v3/calendars/")
+            .split('/')
+            .collect();
+        let reply = match (method, segments.as_slice()) {
+            ("POST", [cal, "events"]) => inner.insert(cal, body),
+            ("PATCH", [cal, "events", id]) => inner.patch(cal, id, body),
+            ("DELETE", [cal, "events", id]) => inner.delete(cal, id),
+            _ => Reply::status(StatusCode::NOT_FOUND),
+        };
+
+        out.push_str(&format!(
+            "--{boundary}\r\nContent-Type: application/http\r\nContent-ID: <response-{content_id}>\r\n\r\nHTTP/1.1 {}\r\n",
+            reply.status
+        ));
+        for (k, v) in &reply.headers {
+            out.push_str(&format!("{k}: {v}\r\n"));
+        }
+        match &reply.body {
+            Some(body) => out.push_str(&format!(
+                "Content-Type: application/json; charset=UTF-8\r\n\r\n{body}\r\n"
+            )),
+            None => out.push_str("\r\n"),
+        }
+    }
+    out.push_str(&format!("--{boundary}--\r\n"));
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            format!("multipart/mixed; boundary={boundary}"),
+        )],
+        out,
+    )
+        .into_response()
+}
+
+async fn token(
+    State(fake): State<FakeGcal>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Json<Value>, StatusCode> {
+    let field = |k: &str| form.get(k).map(String::as_str);
+    let is_client =
+        field("client_id") == Some(CLIENT_ID) && field("client_secret") == Some(CLIENT_SECRET);
+
+    let mut inner = fake.inner.lock().unwrap();
+    match field("grant_type") {
+        Some("refresh_token") if is_client && field("refresh_token") == Some(REFRESH_TOKEN) => {
+            inner.refreshes += 1;
+
+            Ok(Json(json!({
+                "access_token": format!("fake-access-{}", inner.refreshes),
+                "expires_in": 3599,
+                "token_type": "Bearer",
+                "scope": "https://www.googleapis.com/auth/calendar.events",
+            })))
+        }
+        Some("authorization_code") if is_client => {
+            let code = field("code").ok_or(StatusCode::BAD_REQUEST)?;
+            let (challenge, redirect_uri) =
+                inner.codes.remove(code).ok_or(StatusCode::BAD_REQUEST)?;
+            let verifier = field("code_verifier").ok_or(StatusCode::BAD_REQUEST)?;
+            if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier)) != challenge
+                || field("redirect_uri") != Some(&redirect_uri)
+            {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            Ok(Json(json!({
+                "access_token": "fake-access-consent",
+                "refresh_token": REFRESH_TOKEN,
+                "expires_in": 3599,
+                "token_type": "Bearer",
+                "scope": "https://www.googleapis.com/auth/calendar.events",
+            })))
+        }
+        Some("urn:ietf:params:oauth:grant-type:jwt-bearer") => {
+            let mut validation = Validation::new(Algorithm::RS256);
+            validation.set_audience(&[&fake.endpoints.token]);
+            validation.set_issuer(&[SERVICE_ACCOUNT]);
+            let key = DecodingKey::from_rsa_pem(SERVICE_ACCOUNT_PUBLIC_KEY.as_bytes()).unwrap();
+            let claims = jsonwebtoken::decode::<Value>(
+                field("assertion").ok_or(StatusCode::BAD_REQUEST)?,
+                &key,
+                &validation,
+            )
+            .map_err(|_| StatusCode::BAD_REQUEST)?
+            .claims;
+            if !claims["scope"]
+                .as_str()
+                .is_some_and(|s| s.contains("auth/calendar.events"))
+            {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            Ok(Json(json!({
+                "access_token": "fake-access-service",
+                "expires_in": 3599,
+                "token_type": "Bearer",
+            })))
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
@@ -0,0 +1,1144 @@
+//! End-to-end tests, syncing fixture org trees to [`fake_gcal`], plus tests of the CalDAV
+//! backend against [`fake_caldav`] and of the calendars we write with `--ics`.
+//!
+//! Fixtures live in `tests/fixtures`. Since only upcoming timestamps are synced, dates in them
+//! are written relative to today: `{{+2}}` is the day after tomorrow. The exception is
+//! `tests/fixtures/ics`, which is what we expect `--ics` to write for some fixed events.
+
+mod fake_caldav;
+mod fake_gcal;
+
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+
+use color_eyre::eyre::{eyre, WrapErr};
+use jiff::{
+    civil::{date, Date},
+    tz::TimeZone,
+    ToSpan, Zoned,
+};
+use orgize::TextRange;
+use tempfile::TempDir;
+
+use crate::{
+    backend::{CalendarBackend, Origin, SyncEvent},
+    caldav::CalDavBackend,
+    config::{Auth, Config, Dst},
+    gcal::{self, GoogleBackend},
+    ics, import,
+    oauth::{self, ClientCreds},
+    org::{self, Dateish, Reminder, ReminderMethod, TimestampKind},
+    report::{AuthFailed, RunReport, Status},
+    retry::{Retry, Throttled},
+    state::SyncState,
+    sync,
+    token::{Token, TokenStore},
+};
+use fake_caldav::FakeCalDav;
+use fake_gcal::FakeGcal;
+
+/// Copies the fixture tree `name` somewhere we can modify it, filling in its dates.
+fn fixture(name: &str) -> TempDir {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let dir = tempfile::tempdir().unwrap();
+    let today = Zoned::now().date();
+
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let data = fs::read_to_string(entry.path()).unwrap();
+        fs::write(dir.path().join(entry.file_name()), fill_dates(&data, today)).unwrap();
+    }
+
+    dir
+}
+
+/// Replaces every `{{+N}}` or `{{-N}}` with the date `N` days from `today`.
+fn fill_dates(data: &str, today: Date) -> String {
+    let mut out = String::new();
+    let mut rest = data;
+    while let Some(start) = rest.find("{{") {
+        let end = start + rest[start..].find("}}").unwrap();
+        let days: i64 = rest[start + 2..end]
+            .trim_start_matches('+')
+            .parse()
+            .unwrap();
+        let day = today.checked_add(jiff::Span::new().days(days)).unwrap();
+
+        out.push_str(&rest[..start]);
+        out.push_str(&day.strftime("%Y-%m-%d %a").to_string());
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// Everything goes to one calendar called Personal.
+fn single_config() -> Config {
+    let mut config: Config = toml::from_str(
+        r#"
+        default_calendar = "personal"
+
+        [calendars.personal]
+        name = "Personal"
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    config
+}
+
+/// Work and family items go to their own calendars.
+fn routed_config() -> Config {
+    let mut config: Config = toml::from_str(
+        r#"
+        default_calendar = "personal"
+
+        [calendars.personal]
+        name = "Personal"
+
+        [calendars.work]
+        name = "Work"
+
+        [calendars.family]
+        name = "Family"
+
+        [[rules]]
+        calendar = "work"
+        tags = ["w"]
+
+        [[rules]]
+        calendar = "family"
+        tags = ["big_event"]
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    config
+}
+
+fn options(config: &Config) -> org::Options {
+    org::Options {
+        todo_keywords: config.todo_keywords.clone(),
+        done_keywords: config.done_keywords.clone(),
+        default_duration: config.default_duration(),
+        all_day: org::AllDay::All,
+        all_day_tags: vec![],
+        clocks: config.clock.is_some(),
+        tz: config.time_zone(),
+        dst: config.dst.disambiguation(),
+        ignore: config.ignore_timestamps_in.clone(),
+    }
+}
+
+/// Does what a run of cal-sync does, minus authentication.
+async fn try_run(
+    fake: &FakeGcal,
+    root: &Path,
+    config: &Config,
+    state: &mut SyncState,
+) -> sync::Outcome {
+    let items = org::get_valid_items(root.to_path_buf(), &options(config)).items;
+    // Back off just long enough to be sure we do.
+    let retry = Retry {
+        attempts: 5,
+        base: Duration::from_millis(1),
+        max: Duration::from_millis(10),
+    };
+
+    let mut backends = BTreeMap::new();
+    for (id, cal) in &config.calendars {
+        let backend = GoogleBackend::new(fake.session(), &cal.name)
+            .await
+            .map(|b| Throttled::new(b, config.concurrency, retry.clone()));
+        backends.insert(id.clone(), backend);
+    }
+
+    let calendars = sync::sync_events(items, config, root);
+    sync::sync_calendars(&backends, calendars, Some(state), false, false).await
+}
+
+/// Like [`try_run`], but everything has to work.
+async fn run(fake: &FakeGcal, root: &Path, config: &Config, state: &mut SyncState) {
+    let outcome = try_run(fake, root, config, state).await;
+    assert!(
+        outcome.failed.is_empty() && outcome.failures.is_empty(),
+        "{outcome:?}"
+    );
+}
+
+fn find<'a>(
+    events: &'a [google_calendar::types::Event],
+    summary: &str,
+) -> &'a google_calendar::types::Event {
+    events
+        .iter()
+        .find(|e| e.summary == summary)
+        .unwrap_or_else(|| panic!("No event {summary}"))
+}
+
+/// The length of a timed event in minutes.
+fn minutes(ev: &google_calendar::types::Event) -> i64 {
+    let start = ev.start.as_ref().and_then(Dateish::from_gcal);
+    let end = ev.end.as_ref().and_then(Dateish::from_gcal);
+    match (start, end) {
+        (Some(Dateish::Precise(s)), Some(Dateish::Precise(e))) => {
+            (e.timestamp().as_second() - s.timestamp().as_second()) / 60
+        }
+        other => panic!("Not a timed event: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn syncs_upcoming_items() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    let events = fake.events("Personal");
+    let mut summaries: Vec<_> = events.iter().map(|e| e.summary.as_str()).collect();
+    summaries.sort();
+    assert_eq!(
+        summaries,
+        [
+            "TS: Birthday",
+            "TS: Client review",
+            "TS: Dentist",
+            "TS: Standup"
+        ]
+    );
+
+    let standup = find(&events, "TS: Standup");
+    assert_eq!(minutes(standup), 15);
+    assert!(standup.description.starts_with("Daily sync with the team."));
+    assert_eq!(minutes(find(&events, "TS: Client review")), 45);
+    assert_eq!(minutes(find(&events, "TS: Dentist")), 60);
+    assert_eq!(find(&events, "TS: Dentist").location, "12 Main St");
+
+    let birthday = find(&events, "TS: Birthday");
+    let day = Zoned::now()
+        .date()
+        .checked_add(jiff::Span::new().days(5))
+        .unwrap();
+    assert!(matches!(
+        birthday.start.as_ref().and_then(Dateish::from_gcal),
+        Some(Dateish::AllDay(d)) if d == day
+    ));
+}
+
+#[tokio::test]
+async fn resyncing_changes_nothing() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+    let writes = fake.writes();
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    assert_eq!(writes, 4);
+    assert_eq!(fake.writes(), writes);
+}
+
+#[tokio::test]
+async fn follows_edits_to_org_files() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    let personal = dir.path().join("personal.org");
+    let data = fs::read_to_string(&personal).unwrap();
+    fs::write(&personal, data.replace("* Dentist", "* DONE Dentist")).unwrap();
+    let work = dir.path().join("work.org");
+    let data = fs::read_to_string(&work).unwrap();
+    fs::write(&work, data.replace(":EFFORT:   0:45", ":EFFORT:   1:30")).unwrap();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    let events = fake.events("Personal");
+    assert_eq!(events.len(), 3);
+    assert!(events.iter().all(|e| e.summary != "TS: Dentist"));
+    assert_eq!(minutes(find(&events, "TS: Client review")), 90);
+}
+
+#[tokio::test]
+async fn routes_by_tag() {
+    let fake = FakeGcal::start(&["Personal", "Work", "Family"]).await;
+    let dir = fixture("basic");
+    let config = routed_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    assert_eq!(fake.events("Work").len(), 2);
+    assert_eq!(fake.events("Family").len(), 1);
+    assert_eq!(fake.events("Personal").len(), 1);
+
+    // Untagging an item moves its event.
+    let work = dir.path().join("work.org");
+    let data = fs::read_to_string(&work).unwrap();
+    fs::write(&work, data.replace("* TODO Standup :w:", "* TODO Standup")).unwrap();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    assert_eq!(fake.events("Work").len(), 1);
+    find(&fake.events("Personal"), "TS: Standup");
+}
+
+#[tokio::test]
+async fn syncs_clocked_time() {
+    let fake = FakeGcal::start(&["Personal", "Timesheet"]).await;
+    let dir = fixture("basic");
+    let mut config: Config = toml::from_str(
+        r#"
+        default_calendar = "personal"
+
+        [calendars.personal]
+        name = "Personal"
+
+        [calendars.timesheet]
+        name = "Timesheet"
+
+        [clock]
+        calendar = "timesheet"
+        colors = { acme = "5" }
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    let events = fake.events("Timesheet");
+    assert_eq!(events.len(), 3);
+    assert!(events.iter().all(|ev| ev.color_id == "5"));
+
+    // Done entries still count.
+    assert_eq!(minutes(find(&events, "Kickoff")), 90);
+    assert_eq!(minutes(find(&events, "Standup")), 20);
+    // The running clock ends about now.
+    assert!(minutes(find(&events, "Client review")) <= 24 * 60);
+
+    // Clocked time doesn't show up anywhere else.
+    assert_eq!(fake.events("Personal").len(), 4);
+}
+
+#[tokio::test]
+async fn sets_reminders() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("reminders");
+    let mut config: Config = toml::from_str(
+        r#"
+        default_calendar = "personal"
+
+        [calendars.personal]
+        name = "Personal"
+        reminders = "10min"
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    let events = fake.events("Personal");
+    let reminders = |summary: &str| {
+        let r = find(&events, &format!("TS: {summary}"))
+            .reminders
+            .clone()
+            .unwrap();
+        assert!(!r.use_default);
+        r.overrides
+            .iter()
+            .map(|o| (o.method.clone(), o.minutes))
+            .collect::<Vec<_>>()
+    };
+
+    // The calendar's reminder, plus the deadline's warning period.
+    assert_eq!(
+        reminders("Taxes"),
+        [
+            ("popup".to_string(), 10),
+            ("popup".to_string(), 3 * 24 * 60)
+        ]
+    );
+    // The item's own reminders replace the calendar's.
+    assert_eq!(
+        reminders("Call mom"),
+        [("popup".to_string(), 30), ("email".to_string(), 24 * 60)]
+    );
+    assert_eq!(reminders("Gym"), [("popup".to_string(), 10)]);
+}
+
+#[tokio::test]
+async fn filters_items() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("filters");
+    let mut config = single_config();
+    config.title = "{todo} {priority} {title}".to_string();
+    config.filter.include = vec![r#"PRIORITY<"B"|+errand"#.to_string()];
+    config.validate().unwrap();
+
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    // Commented, archived and noexport trees are left out, whatever their children match.
+    let mut summaries: Vec<_> = fake
+        .events("Personal")
+        .into_iter()
+        .map(|e| e.summary)
+        .collect();
+    summaries.sort();
+    assert_eq!(summaries, ["TODO Buy milk", "TODO [#A] Pay rent"]);
+}
+
+#[test]
+fn attributes_timestamps() {
+    let dir = fixture("contexts");
+    let config = single_config();
+
+    let items = org::get_valid_items(dir.path().to_path_buf(), &options(&config)).items;
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].outline, ["Review"]);
+
+    // The SCHEDULED time repeated in the body is only synced once, and the ones in the drawer
+    // and the source block not at all.
+    let found: Vec<_> = items[0]
+        .timestamps
+        .iter()
+        .map(|ts| (ts.kind, ts.line))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (TimestampKind::Scheduled, 2),
+            (TimestampKind::Range, 6),
+            (TimestampKind::Plain, 14)
+        ]
+    );
+}
+
+#[test]
+fn skips_unreadable_files() {
+    let dir = fixture("basic");
+    let config = single_config();
+
+    // Not a file at all, so it can't be read, even as root.
+    fs::create_dir(dir.path().join("broken.org")).unwrap();
+    let cafe = fill_dates("* Café\n<{{+1}} 10:00>\n", Zoned::now().date());
+    let latin1: Vec<u8> = cafe.chars().map(|c| c as u8).collect();
+    fs::write(dir.path().join("latin1.org"), latin1).unwrap();
+    fs::write(
+        dir.path().join("dates.org"),
+        "* Leap day\n<2026-02-30 Mon>\n",
+    )
+    .unwrap();
+
+    let parsed = org::get_valid_items(dir.path().to_path_buf(), &options(&config));
+    assert_eq!(parsed.skipped.len(), 1);
+    assert!(parsed.skipped[0].path.ends_with("broken.org"));
+
+    // Everything else is still read.
+    assert!(parsed.items.iter().any(|i| i.name == "Café"));
+    assert!(parsed
+        .items
+        .iter()
+        .any(|i| i.path.ends_with("personal.org")));
+    let warnings: Vec<_> = parsed
+        .warnings
+        .iter()
+        .map(|w| (w.path.file_name().unwrap().to_str().unwrap(), w.line))
+        .collect();
+    assert_eq!(warnings, [("dates.org", Some(2)), ("latin1.org", None)]);
+}
+
+#[tokio::test]
+async fn reads_times_in_their_time_zone() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("timezones");
+    let mut config = single_config();
+    config.tz = Some("Europe/Berlin".to_string());
+    config.dst = Dst::Reject;
+    config.validate().unwrap();
+
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    let events = fake.events("Personal");
+    let zone = |summary: &str| {
+        find(&events, &format!("TS: {summary}"))
+            .start
+            .as_ref()
+            .unwrap()
+            .time_zone
+            .clone()
+    };
+    // Inherited from the parent headline.
+    assert_eq!(zone("Dinner"), "Asia/Tokyo");
+    assert_eq!(zone("Call"), "Europe/Berlin");
+    // 02:30 doesn't exist that night in New York, so it's skipped rather than guessed at.
+    assert!(!events.iter().any(|e| e.summary == "TS: Flight home"));
+}
+
+#[tokio::test]
+async fn imports_events() {
+    let fake = FakeGcal::start(&["Personal", "Work"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    // Our own events aren't imported.
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    let day = Zoned::now().date().tomorrow().unwrap();
+    let at = |h| day.at(h, 0, 0, 0).to_zoned(TimeZone::UTC).unwrap();
+    let review = fake.add_event(
+        "Work",
+        serde_json::json!({
+            "summary": "Design review",
+            "location": "Room 4",
+            "start": { "dateTime": at(15).timestamp().to_string() },
+            "end": { "dateTime": at(16).timestamp().to_string() },
+            "attendees": [
+                { "email": "ana@example.com", "displayName": "Ana" },
+                { "email": "bo@example.com" },
+            ],
+            "description": "Agenda:\n* goals",
+        }),
+    );
+    let offsite = fake.add_event(
+        "Work",
+        serde_json::json!({
+            "summary": "Offsite",
+            "start": { "date": day.to_string() },
+            "end": { "date": day.checked_add(2.days()).unwrap().to_string() },
+        }),
+    );
+
+    let out = tempfile::tempdir().unwrap();
+    let path = out.path().join("calendar.org");
+    let window = import::parse_window("-7d..+60d", &Zoned::now()).unwrap();
+    let calendars = ["Personal".to_string(), "Work".to_string()];
+
+    let imported = import::run(
+        fake.session(),
+        &calendars,
+        window.clone(),
+        &path,
+        &TimeZone::UTC,
+    )
+    .await
+    .unwrap();
+    assert_eq!(imported, (2, true));
+    let next = day.tomorrow().unwrap().strftime("%Y-%m-%d %a");
+    let day = day.strftime("%Y-%m-%d %a");
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        format!(
+            "\
+# Imported by cal-sync; changes to this file are overwritten.
+
+* Offsite
+:PROPERTIES:
+:ID: gcal-{offsite}
+:GCAL_ID: {offsite}
+:GCAL_CALENDAR: Work
+:END:
+<{day}>--<{next}>
+
+* Design review
+:PROPERTIES:
+:ID: gcal-{review}
+:GCAL_ID: {review}
+:GCAL_CALENDAR: Work
+:LOCATION: Room 4
+:END:
+<{day} 15:00-16:00>
+Attendees: Ana <ana@example.com>, bo@example.com
+
+Agenda:
+ * goals
+"
+        )
+    );
+    // Nothing changed, so nothing's written.
+    let imported = import::run(fake.session(), &calendars, window, &path, &TimeZone::UTC)
+        .await
+        .unwrap();
+    assert_eq!(imported, (2, false));
+
+    // Imported events aren't synced back.
+    let items = org::get_valid_items(out.path().to_path_buf(), &options(&config)).items;
+    assert!(sync::sync_events(items, &config, out.path())
+        .values()
+        .all(Vec::is_empty));
+
+    // Files that can't be read aren't overwritten, even if they might be ours.
+    let latin1 = out.path().join("latin1.org");
+    fs::write(&latin1, b"* Caf\xe9\n").unwrap();
+    let window = import::parse_window("-7d..+60d", &Zoned::now()).unwrap();
+    let res = import::run(fake.session(), &calendars, window, &latin1, &TimeZone::UTC).await;
+    assert!(res.is_err());
+    assert_eq!(fs::read(&latin1).unwrap(), b"* Caf\xe9\n");
+}
+
+#[tokio::test]
+async fn refreshes_the_access_token() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let creds = dir.path().join("creds.json");
+    fs::write(
+        &creds,
+        serde_json::json!({
+            "installed": {
+                "client_id": fake_gcal::CLIENT_ID,
+                "client_secret": fake_gcal::CLIENT_SECRET,
+            }
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let token = dir.path().join("token.json");
+    fs::write(
+        &token,
+        serde_json::json!({
+            "token_type": "Bearer",
+            "access_token": "expired",
+            "expires_in": 0,
+            "refresh_token": fake_gcal::REFRESH_TOKEN,
+            "refresh_token_expires_in": 0,
+            "scope": "",
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let connect = || {
+        gcal::get_client(
+            Auth::Loopback,
+            creds.clone(),
+            Some(TokenStore::new(token.clone(), None)),
+            None,
+            &fake.endpoints,
+        )
+    };
+    let session = connect().await.unwrap();
+    assert_eq!(fake.refreshes(), 1);
+
+    let saved: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&token).unwrap()).unwrap();
+    assert_eq!(saved["access_token"], "fake-access-1");
+    assert_eq!(saved["refresh_token"], fake_gcal::REFRESH_TOKEN);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&token).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // The refreshed session talks to the fake too.
+    GoogleBackend::new(session, "Personal").await.unwrap();
+
+    // It's good for a while yet, so it isn't refreshed again.
+    let session = connect().await.unwrap();
+    assert!(!session.expires_soon());
+    assert_eq!(fake.refreshes(), 1);
+
+    // Until it's about to expire, when callers that stay running get a new one.
+    let store = TokenStore::new(token.clone(), None);
+    let mut tok = store.load().unwrap().unwrap();
+    tok.expires_at = Some(jiff::Timestamp::now().as_second() + 60);
+    store.save(&tok).unwrap();
+    let session = gcal::refresh(Auth::Loopback, &creds, Some(&store), &fake.endpoints)
+        .await
+        .unwrap();
+    assert!(!session.expires_soon());
+    assert_eq!(fake.refreshes(), 2);
+    assert_eq!(store.load().unwrap().unwrap().access_token, "fake-access-2");
+}
+
+#[test]
+fn encrypts_tokens_with_a_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("token.json");
+    let tok = Token {
+        access_token: "secret-access".to_string(),
+        refresh_token: "secret-refresh".to_string(),
+        expires_at: Some(0),
+    };
+
+    let store = TokenStore::new(path.clone(), Some("hunter2".to_string()));
+    store.save(&tok).unwrap();
+    assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+    assert_eq!(
+        store.load().unwrap().unwrap().refresh_token,
+        "secret-refresh"
+    );
+
+    let wrong = TokenStore::new(path.clone(), Some("hunter3".to_string()));
+    assert!(wrong.load().is_err());
+    assert!(TokenStore::new(path.clone(), None).load().is_err());
+
+    // A half-written file says what's wrong with it rather than failing some other way.
+    fs::write(&path, "{\"access_token\": \"sec").unwrap();
+    let err = store.load().unwrap_err();
+    assert!(format!("{err}").contains("corrupt"));
+}
+
+fn client_creds() -> ClientCreds {
+    ClientCreds {
+        client_id: fake_gcal::CLIENT_ID.to_string(),
+        client_secret: fake_gcal::CLIENT_SECRET.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn signs_in_through_the_loopback_listener() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let http = reqwest::Client::new();
+
+    let mut browser = None;
+    let tok = oauth::loopback(&http, &fake.endpoints, &client_creds(), None, |url| {
+        let redirect = fake.consent(url);
+        browser = Some(tokio::spawn(async move {
+            // Someone else's redirect is turned away before ours gets there.
+            let forged = redirect.replace("state=", "state=forged");
+            let forged = reqwest::get(forged).await.unwrap().status();
+            let ours = reqwest::get(redirect).await.unwrap().status();
+            (forged, ours)
+        }));
+    })
+    .await
+    .unwrap();
+
+    let (forged, ours) = browser.unwrap().await.unwrap();
+    assert_eq!(forged, 400);
+    assert_eq!(ours, 200);
+    assert_eq!(tok["access_token"], "fake-access-consent");
+    assert_eq!(tok["refresh_token"], fake_gcal::REFRESH_TOKEN);
+}
+
+#[tokio::test]
+async fn signs_in_by_pasting_the_redirect() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let http = reqwest::Client::new();
+
+    let tok = oauth::manual(&http, &fake.endpoints, &client_creds(), |url| {
+        Ok(fake.consent(url))
+    })
+    .await
+    .unwrap();
+    assert_eq!(tok["access_token"], "fake-access-consent");
+
+    let forged = oauth::manual(&http, &fake.endpoints, &client_creds(), |url| {
+        Ok(fake.consent(url).replace("state=", "state=forged"))
+    })
+    .await;
+    assert!(forged.is_err());
+}
+
+#[tokio::test]
+async fn signs_in_as_a_service_account() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let key =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/auth/service-account.json");
+
+    let (tok, expires_at) = oauth::service_account(&reqwest::Client::new(), &fake.endpoints, &key)
+        .await
+        .unwrap();
+    assert_eq!(tok, "fake-access-service");
+    assert!(expires_at.is_some());
+
+    let session = gcal::get_client(Auth::ServiceAccount, key, None, None, &fake.endpoints)
+        .await
+        .unwrap();
+    GoogleBackend::new(session, "Personal").await.unwrap();
+}
+
+#[tokio::test]
+async fn plans_without_changing_anything() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+    let writes = fake.writes();
+
+    let personal = dir.path().join("personal.org");
+    let data = fs::read_to_string(&personal).unwrap();
+    fs::write(&personal, data.replace("* Dentist", "* Dentist again")).unwrap();
+
+    let items = org::get_valid_items(dir.path().to_path_buf(), &options(&config)).items;
+    let events = sync::sync_events(items, &config, dir.path())
+        .remove("personal")
+        .unwrap();
+    let backend = GoogleBackend::new(fake.session(), "Personal")
+        .await
+        .unwrap();
+    let plan = sync::plan(&backend, events, state.calendars.get("personal"), false)
+        .await
+        .unwrap();
+
+    // Retitling a headline without an ID changes its key.
+    assert_eq!(plan.inserts.len(), 1);
+    assert_eq!(plan.inserts[0].summary, "TS: Dentist again");
+    assert_eq!(plan.deletes.len(), 1);
+    assert_eq!(plan.deletes[0].summary, "TS: Dentist");
+    assert_eq!(plan.unchanged.len(), 3);
+    assert_eq!(fake.writes(), writes);
+}
+
+#[tokio::test]
+async fn writes_moved_events_back() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    // Someone moves the dentist to two hours later the next day.
+    fake.edit_event("Personal", "TS: Dentist", |ev| {
+        for field in ["start", "end"] {
+            let at: jiff::Timestamp = ev[field]["dateTime"].as_str().unwrap().parse().unwrap();
+            ev[field]["dateTime"] = (at + 26.hours()).to_string().into();
+        }
+    });
+
+    let personal = dir.path().join("personal.org");
+    let before = fs::read_to_string(&personal).unwrap();
+    let items = org::get_valid_items(dir.path().to_path_buf(), &options(&config)).items;
+    let events = sync::sync_events(items, &config, dir.path())
+        .remove("personal")
+        .unwrap();
+    let backend = GoogleBackend::new(fake.session(), "Personal")
+        .await
+        .unwrap();
+    let applied = sync::sync(&backend, events, state.calendars.get_mut("personal"), true)
+        .await
+        .unwrap();
+    assert!(applied.conflicts.is_empty());
+    assert!(applied.failures.is_empty());
+
+    // Only the timestamp changed; every other byte of the file is as it was.
+    let today = Zoned::now().date();
+    let start = today
+        .checked_add(3.days())
+        .unwrap()
+        .at(9, 0, 0, 0)
+        .to_zoned(config.time_zone())
+        .unwrap();
+    let moved = start.checked_add(26.hours()).unwrap();
+    let old = fill_dates("<{{+3}} 09:00>", today);
+    let new = moved.strftime("<%Y-%m-%d %a %H:%M>").to_string();
+    assert_eq!(
+        fs::read_to_string(&personal).unwrap(),
+        before.replacen(&old, &new, 1)
+    );
+
+    // And the move sticks, rather than being synced back.
+    let writes = fake.writes();
+    run(&fake, dir.path(), &config, &mut state).await;
+    assert_eq!(fake.writes(), writes);
+}
+
+#[tokio::test]
+async fn batches_writes() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    run(&fake, dir.path(), &config, &mut state).await;
+    assert_eq!(fake.writes(), 4);
+    assert_eq!(fake.batches(), 1);
+
+    // Moving one event and dropping another is a patch and a delete, in a batch each.
+    let work = dir.path().join("work.org");
+    let data = fs::read_to_string(&work).unwrap();
+    fs::write(
+        &work,
+        data.replace("14:00", "15:00")
+            .replace("* TODO Standup", "* DONE Standup"),
+    )
+    .unwrap();
+    run(&fake, dir.path(), &config, &mut state).await;
+
+    assert_eq!(fake.writes(), 6);
+    assert_eq!(fake.batches(), 3);
+    assert_eq!(fake.events("Personal").len(), 3);
+}
+
+#[tokio::test]
+async fn retries_rate_limits() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+
+    fake.fail_next(&[429, 403, 503]);
+    run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+
+    assert_eq!(fake.events("Personal").len(), 4);
+}
+
+#[tokio::test]
+async fn reports_failures_without_stopping() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+    let mut state = SyncState::default();
+
+    // Bad requests aren't worth retrying.
+    fake.fail_next(&[400]);
+    let outcome = try_run(&fake, dir.path(), &config, &mut state).await;
+
+    assert_eq!(outcome.failures.len(), 1);
+    assert_eq!(outcome.failures[0].1.op, "insert");
+    assert!(outcome.report().is_err());
+    assert_eq!(fake.events("Personal").len(), 3);
+
+    // The next run picks up where this one left off.
+    run(&fake, dir.path(), &config, &mut state).await;
+    assert_eq!(fake.events("Personal").len(), 4);
+}
+
+#[tokio::test]
+async fn reports_runs_for_monitoring() {
+    let fake = FakeGcal::start(&["Personal"]).await;
+    let dir = fixture("basic");
+    let config = single_config();
+
+    fake.fail_next(&[400]);
+    let outcome = try_run(&fake, dir.path(), &config, &mut SyncState::default()).await;
+    let mut report = RunReport::new(&org::Parsed::default());
+    report.add_outcome(&outcome);
+
+    assert_eq!(report.status, Status::Partial);
+    assert_eq!(report.exit_code, 4);
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["calendars"]["personal"]["inserted"], 3);
+    assert_eq!(json["calendars"]["personal"]["failed"], 1);
+    assert_eq!(json["failures"][0]["op"], "insert");
+
+    // Problems with org files are reported per file.
+    let landing = "* Landing\n:PROPERTIES:\n:TIMEZONE: Moon/Tranquility\n:END:\n<{{+1}} 10:00>\n";
+    fs::write(
+        dir.path().join("moon.org"),
+        fill_dates(landing, Zoned::now().date()),
+    )
+    .unwrap();
+    let parsed = org::get_valid_items(dir.path().to_path_buf(), &options(&config));
+    let report = RunReport::new(&parsed);
+    assert_eq!(report.status, Status::OrgProblems);
+    assert_eq!(report.exit_code, 3);
+    let moon = dir.path().join("moon.org").to_string_lossy().into_owned();
+    assert_eq!(report.warnings[&moon].len(), 1);
+    assert_eq!(report.warnings[&moon][0].line, Some(1));
+
+    // Not being able to sign in is told apart from other failures.
+    let mut report = RunReport::new(&org::Parsed::default());
+    report.fail(&eyre!("invalid_grant").wrap_err(AuthFailed));
+    assert_eq!(report.status, Status::AuthFailed);
+    assert_eq!(report.exit_code, 2);
+}
+
+/// An hour-long event at 9:00 on the 15th of March 2030.
+fn event(key: &str, summary: &str) -> SyncEvent {
+    let tz = TimeZone::get("Europe/Berlin").unwrap();
+    let at = |h| {
+        Dateish::Precise(
+            date(2030, 3, 15)
+                .at(h, 0, 0, 0)
+                .to_zoned(tz.clone())
+                .unwrap(),
+        )
+    };
+
+    SyncEvent {
+        key: key.to_string(),
+        summary: summary.to_string(),
+        description: String::new(),
+        location: None,
+        start: at(9),
+        end: Some(at(10)),
+        recurrence: None,
+        color: String::new(),
+        reminders: None,
+        origin: Origin {
+            path: "events.org".into(),
+            range: TextRange::default(),
+            raw: String::new(),
+            has_end: true,
+            clocked: false,
+            line: 1,
+        },
+    }
+}
+
+#[tokio::test]
+async fn syncs_to_caldav() {
+    let fake = FakeCalDav::start().await;
+    let backend = CalDavBackend::new(&fake.url, None, None).unwrap();
+    let theirs = fake.add_resource(
+        "theirs.ics",
+        "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:theirs\r\nSUMMARY:Lunch & learn\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+    );
+
+    let dentist = event("dentist", "Dentist");
+    let standup = event("standup", "Standup; daily, <early> & short");
+    backend.insert(&dentist).await.unwrap();
+    backend.insert(&standup).await.unwrap();
+    // New events never overwrite anything that's already there.
+    let writes = fake.writes();
+    assert_eq!(writes.len(), 2);
+    assert!(writes
+        .iter()
+        .all(|(w, create)| w.starts_with("PUT ") && *create));
+
+    // Our events are found again by their key and hash, and nobody else's are listed.
+    let mut listed = backend.list().await.unwrap();
+    listed.sort_by(|a, b| a.summary.cmp(&b.summary));
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].summary, dentist.summary);
+    assert_eq!(listed[0].key.as_deref(), Some("dentist"));
+    assert_eq!(listed[0].hash, Some(dentist.hash()));
+    // Which isn't confused by the time zone's own DTSTARTs.
+    assert_eq!(
+        listed[0].start.as_ref().map(Dateish::canonical),
+        Some(dentist.start.canonical())
+    );
+    assert_eq!(
+        listed[0].end.as_ref().map(Dateish::canonical),
+        dentist.end.as_ref().map(Dateish::canonical)
+    );
+    assert_eq!(listed[1].summary, standup.summary);
+    assert_eq!(listed[1].hash, Some(standup.hash()));
+
+    // Updates overwrite what's there, rather than requiring that nothing is.
+    let moved = event("dentist", "Dentist, rescheduled");
+    backend.update(&listed[0].id, &moved).await.unwrap();
+    assert_eq!(fake.writes()[2], (format!("PUT {}", listed[0].id), false));
+    assert!(fake.resources()[&listed[0].id].contains("SUMMARY:Dentist\\, rescheduled"));
+
+    backend.delete(&listed[1].id).await.unwrap();
+    assert_eq!(fake.resources().len(), 2);
+    assert!(fake.resources().contains_key(&theirs));
+}
+
+#[tokio::test]
+async fn treats_caldav_deletes_of_missing_events_as_done() {
+    let fake = FakeCalDav::start().await;
+    let backend = CalDavBackend::new(&fake.url, None, None).unwrap();
+
+    backend.delete("gone.ics").await.unwrap();
+    assert_eq!(
+        fake.writes(),
+        [("DELETE /calendars/personal/gone.ics".to_string(), false)]
+    );
+}
+
+#[tokio::test]
+async fn explains_caldav_errors() {
+    let fake = FakeCalDav::start().await;
+    let backend = CalDavBackend::new(&fake.url, None, None).unwrap();
+
+    fake.fail_next(403, "Calendar is read-only");
+    let err = backend.list().await.unwrap_err();
+    let err = format!("{err:#}");
+    assert!(err.contains("403"), "{err}");
+    assert!(err.contains("Calendar is read-only"), "{err}");
+}
+
+#[test]
+fn writes_ics() {
+    let tz = TimeZone::get("Europe/Berlin").unwrap();
+    let at = |d: Date, h, m| Dateish::Precise(d.at(h, m, 0, 0).to_zoned(tz.clone()).unwrap());
+    let event = |key: &str, summary: &str, start, end| SyncEvent {
+        key: key.to_string(),
+        summary: summary.to_string(),
+        description: String::new(),
+        location: None,
+        start,
+        end: Some(end),
+        recurrence: None,
+        color: String::new(),
+        reminders: None,
+        origin: Origin {
+            path: "events.org".into(),
+            range: TextRange::default(),
+            raw: String::new(),
+            has_end: true,
+            clocked: false,
+            line: 1,
+        },
+    };
+
+    let dentist = SyncEvent {
+        description: "Bring the forms; the card, and the letter from Dr. Smith about the crown.\n\
+            Ask about the bill."
+            .to_string(),
+        location: Some("12 Main St, Springfield".to_string()),
+        reminders: Some(vec![
+            Reminder {
+                method: ReminderMethod::Popup,
+                minutes: 10,
+            },
+            Reminder {
+                method: ReminderMethod::Email,
+                minutes: 1440,
+            },
+        ]),
+        ..event(
+            "dentist/0",
+            "Dentist; bring the forms, and the scans from C:\\Scans",
+            at(date(2030, 3, 15), 9, 0),
+            at(date(2030, 3, 15), 10, 0),
+        )
+    };
+    let birthday = event(
+        "birthday/0",
+        "Birthday",
+        Dateish::AllDay(date(2030, 7, 4)),
+        Dateish::AllDay(date(2030, 7, 5)),
+    );
+    let standup = SyncEvent {
+        description: "Weekly".to_string(),
+        recurrence: Some("RRULE:FREQ=WEEKLY;INTERVAL=1;COUNT=10".to_string()),
+        ..event(
+            "standup/0",
+            "Standup with everyone in Zürich, Köln, Malmö and São Paulo, which runs long",
+            at(date(2030, 3, 18), 9, 30),
+            at(date(2030, 3, 18), 9, 45),
+        )
+    };
+
+    let written = ics::calendar([&dentist, &birthday, &standup]);
+    // Every line ends in CRLF, and DTSTAMP is whenever we wrote it.
+    let lines: Vec<String> = written
+        .strip_suffix("\r\n")
+        .unwrap()
+        .split("\r\n")
+        .map(|l| {
+            assert!(!l.contains('\n'), "{l:?}");
+            assert!(l.len() <= 75, "{l:?}");
+            match l.strip_prefix("DTSTAMP:") {
+                Some(stamp) => {
+                    jiff::fmt::strtime::parse("%Y%m%dT%H%M%SZ", stamp).unwrap();
+                    "DTSTAMP:{{now}}".to_string()
+                }
+                None => l.to_string(),
+            }
+        })
+        .collect();
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ics/calendar.ics");
+    let expected = fs::read_to_string(path).unwrap();
+    assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+}
@@ -0,0 +1,186 @@
+//! Where Google tokens are kept between runs.
+//!
+//! A refresh token is as good as a password to the calendar, so the file is only readable by us,
+//! and is replaced in one go so that a crash can't leave half of one behind. Given a passphrase,
+//! it's encrypted too.
+
+use std::{fs, path::PathBuf};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use color_eyre::{
+    eyre::{eyre, OptionExt, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic;
+
+/// Access tokens are refreshed this many seconds before they expire, so they don't run out
+/// mid-sync.
+const EXPIRY_MARGIN: i64 = 5 * 60;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// When the access token expires, in seconds since the epoch. Missing from token files
+    /// written before we kept track, which are refreshed on their next use.
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl Token {
+    /// Reads a token endpoint's response. Google only sends a refresh token the first time, so
+    /// `old_refresh` is kept if there isn't a new one.
+    pub fn from_response(resp: &serde_json::Value, old_refresh: Option<&str>) -> Result<Self> {
+        let access_token = resp["access_token"]
+            .as_str()
+            .ok_or_eyre("No access token in token response")?;
+        let refresh_token = resp["refresh_token"]
+            .as_str()
+            .or(old_refresh)
+            .ok_or_eyre("No refresh token in token response")?;
+        let expires_at = resp["expires_in"]
+            .as_i64()
+            .map(|secs| jiff::Timestamp::now().as_second() + secs);
+
+        Ok(Self {
+            access_token: access_token.to_string(),
+            refresh_token: refresh_token.to_string(),
+            expires_at,
+        })
+    }
+
+    /// Whether the access token has to be refreshed before we use it.
+    pub fn expires_soon(&self) -> bool {
+        match self.expires_at {
+            Some(at) => expires_soon(at),
+            None => true,
+        }
+    }
+}
+
+/// Whether an access token that expires at `at`, in seconds since the epoch, has to be refreshed
+/// before we use it.
+pub fn expires_soon(at: i64) -> bool {
+    at - EXPIRY_MARGIN <= jiff::Timestamp::now().as_second()
+}
+
+/// How an encrypted token file is laid out.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sealed {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// A token file, encrypted if there's a passphrase.
+pub struct TokenStore {
+    path: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl TokenStore {
+    pub fn new(path: PathBuf, passphrase: Option<String>) -> Self {
+        Self { path, passphrase }
+    }
+
+    /// Reads the token, if we've saved one yet.
+    pub fn load(&self) -> Result<Option<Token>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read_to_string(&self.path)
+            .wrap_err_with(|| format!("Couldn't read token {}", self.path.to_string_lossy()))?;
+        let corrupt = || {
+            format!(
+                "Token {} is corrupt; delete it to sign in again",
+                self.path.to_string_lossy()
+            )
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&data).wrap_err_with(corrupt)?;
+        let plain = match serde_json::from_value::<Sealed>(value.clone()) {
+            Ok(sealed) => {
+                let passphrase = self.passphrase.as_deref().ok_or_else(|| {
+                    eyre!(
+                        "Token {} is encrypted, but no passphrase was given",
+                        self.path.to_string_lossy()
+                    )
+                })?;
+                let plain = open(&sealed, passphrase).wrap_err_with(|| {
+                    format!(
+                        "Couldn't decrypt token {}; is the passphrase right?",
+                        self.path.to_string_lossy()
+                    )
+                })?;
+                serde_json::from_slice(&plain).wrap_err_with(corrupt)?
+            }
+            // Written before there was a passphrase, or without one. It'll be encrypted the next
+            // time it's saved.
+            Err(_) => value,
+        };
+
+        Ok(Some(serde_json::from_value(plain).wrap_err_with(corrupt)?))
+    }
+
+    /// Replaces the token on disk with `token`.
+    pub fn save(&self, token: &Token) -> Result<()> {
+        let mut data = serde_json::to_vec_pretty(token)?;
+        if let Some(passphrase) = &self.passphrase {
+            data = serde_json::to_vec_pretty(&seal(&data, passphrase)?)?;
+        }
+
+        atomic::write(&self.path, &data, 0o600)
+            .wrap_err_with(|| format!("Couldn't save token {}", self.path.to_string_lossy()))
+    }
+}
+
+fn seal(plain: &[u8], passphrase: &str) -> Result<Sealed> {
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut salt).map_err(|e| eyre!("Couldn't get random bytes: {e}"))?;
+    getrandom::getrandom(&mut nonce).map_err(|e| eyre!("Couldn't get random bytes: {e}"))?;
+
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(XNonce::from_slice(&nonce), plain)
+        .map_err(|_| eyre!("Couldn't encrypt token"))?;
+
+    Ok(Sealed {
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+fn open(sealed: &Sealed, passphrase: &str) -> Result<Vec<u8>> {
+    let salt = STANDARD.decode(&sealed.salt)?;
+    let nonce = STANDARD.decode(&sealed.nonce)?;
+    let ciphertext = STANDARD.decode(&sealed.ciphertext)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(eyre!("Invalid nonce"));
+    }
+
+    cipher(passphrase, &salt)?
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| eyre!("Authentication failed"))
+}
+
+/// Derives the key for `passphrase` and `salt`.
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| eyre!("Couldn't derive token key: {e}"))?;
+
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
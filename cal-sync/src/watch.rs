@@ -0,0 +1,248 @@
+//! `--watch`: stay running, and re-sync whenever org files change.
+//!
+//! Only the files that changed are re-parsed, and only the calendars whose events changed are
+//! synced. Every so often everything is re-read and synced anyway, both to catch anything the
+//! file watcher missed and because timestamps go stale as time passes.
+//!
+//! We stay running for longer than some sign-ins last, so backends are made again whenever
+//! theirs is about to run out.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use notify::{RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backend::{CalendarBackend, SyncEvent},
+    config::Config,
+    org::{self, stable_hash, AgendaItem},
+    state::SyncState,
+    sync,
+};
+
+/// Gets new backends when the ones we have are about to stop working.
+pub trait Reconnect<B> {
+    /// New backends for every calendar, keyed by id, if the ones we have are about to stop
+    /// working.
+    async fn reconnect(&mut self) -> Result<Option<BTreeMap<String, Result<B>>>>;
+}
+
+/// For backends that keep working for as long as we run.
+pub struct StaysConnected;
+
+impl<B> Reconnect<B> for StaysConnected {
+    async fn reconnect(&mut self) -> Result<Option<BTreeMap<String, Result<B>>>> {
+        Ok(None)
+    }
+}
+
+struct Watch<'a, B, R> {
+    config: &'a Config,
+    options: &'a org::Options,
+    root: &'a Path,
+    backends: BTreeMap<String, Result<B>>,
+    reconnect: R,
+    two_way: bool,
+    /// The valid items in each org file, keyed by absolute path.
+    files: BTreeMap<PathBuf, Vec<AgendaItem>>,
+    /// A digest of the events we last synced to each calendar.
+    synced: BTreeMap<String, String>,
+    state: Option<SyncState>,
+}
+
+/// Syncs everything, then keeps syncing changes until we get SIGTERM or Ctrl-C.
+pub async fn run<B: CalendarBackend, R: Reconnect<B>>(
+    config: &Config,
+    options: &org::Options,
+    root: &Path,
+    backends: BTreeMap<String, Result<B>>,
+    reconnect: R,
+    items: Vec<AgendaItem>,
+    two_way: bool,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        // Only fails once we've stopped listening.
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+
+    let mut files: BTreeMap<PathBuf, Vec<AgendaItem>> = BTreeMap::new();
+    for item in items {
+        files.entry(item.path.clone()).or_default().push(item);
+    }
+    let mut watch = Watch {
+        config,
+        options,
+        root,
+        backends,
+        reconnect,
+        two_way,
+        files,
+        synced: BTreeMap::new(),
+        state: config.state.as_deref().map(SyncState::load).transpose()?,
+    };
+    watch.reconcile().await?;
+
+    let debounce = Duration::from_millis(config.debounce_ms);
+    let mut full_sync = tokio::time::interval(Duration::from_secs(config.full_sync_minutes * 60));
+    // The first tick is immediate, and we just synced.
+    full_sync.tick().await;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    info!("Watching {}", root.to_string_lossy());
+    loop {
+        // A sync in progress is never interrupted, since we only listen for signals in between.
+        tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = full_sync.tick() => {
+                let parsed = org::get_valid_items(root.to_path_buf(), options);
+                for w in &parsed.warnings {
+                    warn!("{w}");
+                }
+                let mut files: BTreeMap<PathBuf, Vec<AgendaItem>> = BTreeMap::new();
+                for item in parsed.items {
+                    files.entry(item.path.clone()).or_default().push(item);
+                }
+                // Files we can't read right now keep what we last read from them.
+                for s in &parsed.skipped {
+                    warn!("Skipping {s}");
+                    if let Some(items) = watch.files.remove(&s.path) {
+                        files.insert(s.path.clone(), items);
+                    }
+                }
+                watch.files = files;
+                watch.synced.clear();
+                watch.reconcile().await?;
+            }
+            Some(res) = rx.recv() => {
+                let mut changed = BTreeSet::new();
+                collect_paths(res, &mut changed);
+
+                // Editors tend to save in bursts, so wait for things to settle down.
+                while let Ok(Some(res)) = tokio::time::timeout(debounce, rx.recv()).await {
+                    collect_paths(res, &mut changed);
+                }
+                if changed.is_empty() {
+                    continue;
+                }
+
+                let now = options.now();
+                for path in changed {
+                    debug!("Changed: {}", path.to_string_lossy());
+                    match org::parse_file(&path, options, &now) {
+                        Ok(parsed) => {
+                            for w in &parsed.warnings {
+                                warn!("{w}");
+                            }
+                            watch.files.insert(path, parsed.items);
+                        }
+                        // Deleted, or renamed away.
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            watch.files.remove(&path);
+                        }
+                        // Keep what we last read, rather than deleting its events.
+                        Err(e) => warn!("Skipping {}: {e}", path.to_string_lossy()),
+                    }
+                }
+                watch.reconcile().await?;
+            }
+        }
+    }
+
+    info!("Shutting down");
+    Ok(())
+}
+
+/// Adds the org files affected by a file system event to `changed`.
+fn collect_paths(res: notify::Result<notify::Event>, changed: &mut BTreeSet<PathBuf>) {
+    match res {
+        Ok(ev) => changed.extend(
+            ev.paths
+                .into_iter()
+                .filter(|p| org::is_org_file(p))
+                .map(|p| std::path::absolute(&p).unwrap_or(p)),
+        ),
+        Err(e) => error!("Watch error: {e}"),
+    }
+}
+
+impl<B: CalendarBackend, R: Reconnect<B>> Watch<'_, B, R> {
+    /// Syncs every calendar whose events changed since we last synced it.
+    async fn reconcile(&mut self) -> Result<()> {
+        let items = self.files.values().flatten().cloned().collect();
+        let mut calendars = sync::sync_events(items, self.config, self.root);
+
+        let mut digests = BTreeMap::new();
+        calendars.retain(|id, events| {
+            let digest = digest(events);
+            let changed = self.synced.get(id) != Some(&digest);
+            digests.insert(id.clone(), digest);
+            changed
+        });
+        if calendars.is_empty() {
+            return Ok(());
+        }
+
+        match self.reconnect.reconnect().await {
+            Ok(Some(backends)) => {
+                info!("Reconnected");
+                self.backends = backends;
+            }
+            Ok(None) => {}
+            // The old backends may work for a little longer; if not, the calendars that fail are
+            // retried later anyway.
+            Err(e) => error!("Couldn't reconnect: {e:#}"),
+        }
+
+        let outcome = sync::sync_calendars(
+            &self.backends,
+            calendars,
+            self.state.as_mut(),
+            self.two_way,
+            false,
+        )
+        .await;
+
+        // Failed calendars are retried on the next change, or the next full sync.
+        if let Err(e) = outcome.report() {
+            error!("{e}");
+        }
+        for id in outcome
+            .failed
+            .iter()
+            .chain(outcome.failures.iter().map(|(id, _)| id))
+        {
+            digests.remove(id);
+        }
+        self.synced.extend(digests);
+
+        if let (Some(path), Some(state)) = (&self.config.state, &self.state) {
+            state.save(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Changes whenever anything about any of `events` does.
+fn digest(events: &[SyncEvent]) -> String {
+    let mut hashes: Vec<String> = events
+        .iter()
+        .map(|ev| format!("{} {}", ev.key, ev.hash()))
+        .collect();
+    hashes.sort();
+
+    stable_hash(hashes.iter().map(|h| h.as_bytes()))
+}
@@ -0,0 +1,141 @@
+//! Rewriting timestamps in org files to match events that were moved in the calendar.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use jiff::{civil::Date, tz::TimeZone, Zoned};
+use tracing::{info, warn};
+
+use crate::{
+    atomic,
+    backend::Origin,
+    org::{self, Dateish},
+};
+
+/// Replace the timestamp at `origin` with one spanning `start` to `end`.
+#[derive(Debug)]
+pub struct Edit {
+    /// The [`SyncEvent::key`](crate::backend::SyncEvent::key) of the event that moved.
+    pub key: String,
+    pub origin: Origin,
+    pub start: Dateish,
+    /// `None` if the timestamp shouldn't get an explicit end.
+    pub end: Option<Dateish>,
+    /// The zone to write times in.
+    pub tz: TimeZone,
+}
+
+/// Applies every edit, leaving everything else in each file untouched. Returns the edits that
+/// couldn't be applied.
+///
+/// If a file changed since we parsed it, or can't be rewritten, none of its edits are applied,
+/// but the other files still are.
+pub fn apply(edits: Vec<Edit>) -> Vec<Edit> {
+    let mut by_file: BTreeMap<PathBuf, Vec<Edit>> = BTreeMap::new();
+    for e in edits {
+        by_file.entry(e.origin.path.clone()).or_default().push(e);
+    }
+
+    let mut skipped = vec![];
+    for (path, mut edits) in by_file {
+        if let Err(e) = apply_file(&path, &mut edits) {
+            warn!("Not writing back to {}: {e:#}", path.to_string_lossy());
+            skipped.extend(edits);
+        }
+    }
+
+    skipped
+}
+
+/// Applies `edits` to the file at `path`, all or nothing.
+fn apply_file(path: &Path, edits: &mut [Edit]) -> Result<()> {
+    // Read it the way the parser did, so that the ranges we got from it line up.
+    let (mut data, latin1) = org::read_text(path)?;
+
+    // Go back to front so that earlier ranges stay valid.
+    edits.sort_by_key(|e| std::cmp::Reverse(e.origin.range.start()));
+    let mut changes = vec![];
+    for e in edits.iter() {
+        // The parsed timestamp can include trailing whitespace, which we leave alone.
+        let old = e.origin.raw.trim_end();
+        let start: usize = e.origin.range.start().into();
+        let end = start + old.len();
+
+        if data.get(start..end) != Some(old) {
+            return Err(eyre!("it changed since it was read"));
+        }
+
+        let new = format_timestamp(old, &e.start, e.end.as_ref(), &e.tz);
+        changes.push(format!("{old} -> {new}"));
+        data.replace_range(start..end, &new);
+    }
+
+    // Timestamps are ASCII, so a Latin-1 file stays Latin-1.
+    let bytes = if latin1 {
+        data.chars().map(|c| c as u8).collect()
+    } else {
+        data.into_bytes()
+    };
+    let tmp = atomic::tmp_path(path);
+    fs::write(&tmp, bytes)?;
+    // A new file gets the umask's permissions, so give it the ones the old one had.
+    fs::set_permissions(&tmp, fs::metadata(path)?.permissions())?;
+    fs::rename(tmp, path)?;
+
+    for change in changes {
+        info!("{}: {change}", path.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+/// Formats an active timestamp, carrying over any repeater and warning cookies from `old`.
+fn format_timestamp(old: &str, start: &Dateish, end: Option<&Dateish>, tz: &TimeZone) -> String {
+    // Cookies live in the first half of a `<a>--<b>` range, after the date and time.
+    let first = old.split("--<").next().unwrap_or(old);
+    let cookies: String = first
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .split_whitespace()
+        .skip(1)
+        .filter(|t| t.starts_with(['+', '.', '-']))
+        .map(|t| format!(" {t}"))
+        .collect();
+
+    match (start, end) {
+        (Dateish::AllDay(s), Some(Dateish::AllDay(e))) if e != s => {
+            format!("<{}{cookies}>--<{}>", day(*s), day(*e))
+        }
+        (Dateish::AllDay(s), _) => format!("<{}{cookies}>", day(*s)),
+        (Dateish::Precise(s), Some(Dateish::Precise(e))) => {
+            let (s, e) = (s.with_time_zone(tz.clone()), e.with_time_zone(tz.clone()));
+            if s.date() == e.date() {
+                format!("<{} {}-{}{cookies}>", day(s.date()), time(&s), time(&e))
+            } else {
+                format!(
+                    "<{} {}{cookies}>--<{} {}>",
+                    day(s.date()),
+                    time(&s),
+                    day(e.date()),
+                    time(&e)
+                )
+            }
+        }
+        (Dateish::Precise(s), _) => {
+            let s = s.with_time_zone(tz.clone());
+            format!("<{} {}{cookies}>", day(s.date()), time(&s))
+        }
+    }
+}
+
+fn day(d: Date) -> String {
+    d.strftime("%Y-%m-%d %a").to_string()
+}
+
+fn time(z: &Zoned) -> String {
+    z.strftime("%H:%M").to_string()
+}